@@ -1,3 +1,4 @@
+use clap::ArgGroup;
 use std::path::PathBuf;
 
 /// A static site generator based on pandoc.
@@ -7,11 +8,23 @@ pub struct Args {
     #[clap(subcommand)]
     pub command: Command,
 
-    #[clap(short, long, value_parser, default_value = "span.yml", global = true)]
-    pub config: PathBuf,
+    /// Config file to use, instead of looking for span.yml, span.toml or span.json
+    /// in the input folder and the folders above it.
+    #[clap(short, long, value_parser, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Profile to use. Defaults to development for serve and production for build,
+    /// if the config defines them.
+    #[clap(long, value_parser, env = "SPAN_PROFILE", global = true)]
+    pub profile: Option<String>,
+
+    /// Profile whose `site` overrides to use on top of the selected profile, without
+    /// its other settings.
+    #[clap(short, long, value_parser, env = "SPAN_ENV", global = true)]
+    pub env: Option<String>,
 }
 
-#[derive(clap::Subcommand, Debug)]
+#[derive(clap::Subcommand, Debug, Clone)]
 pub enum Command {
     Build {
         /// Path to source files.
@@ -31,4 +44,35 @@ pub enum Command {
         #[clap(short, long, value_parser, default_value_t = 3000)]
         port: u16,
     },
+    /// Checks or prints the configuration.
+    #[clap(group(ArgGroup::new("action").required(true).args(&["check", "print", "explain"])))]
+    Config {
+        /// Path to source files.
+        #[clap(default_value = ".", forbid_empty_values = true)]
+        input: PathBuf,
+
+        /// Check the configuration for errors.
+        #[clap(long)]
+        check: bool,
+
+        /// Print the configuration, with defaults filled in and the environment applied.
+        #[clap(long)]
+        print: bool,
+
+        /// Print the settings used for a content file, including those from
+        /// `_span.yml` files in the folders it's in.
+        #[clap(long, value_name = "FILE")]
+        explain: Option<PathBuf>,
+    },
+    /// Manages the cache of results of commands run with `cache: true`.
+    Cache {
+        #[clap(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum CacheCommand {
+    /// Deletes every cached result.
+    Clean,
 }
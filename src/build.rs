@@ -1,88 +1,316 @@
-use crate::config;
+use crate::builtins;
+use crate::cache::Cache;
+use crate::call;
+use crate::config::{self, Hook};
+use crate::data;
+use crate::diagnostic::Diagnostic;
 use crate::errors::*;
+use crate::frontmatter::{Format, FrontMatter};
+use crate::plugins;
+use crate::runner;
+use crate::scripting::Scripts;
+use crate::shell;
 use crate::snippets;
+use crate::stages::{self, Build, Stage};
 use crate::vfs::Folder;
 use error_chain::bail;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, HashSet};
 use std::ffi::OsString;
 use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
-use std::process::{Command, Output, Stdio};
+use std::path::{Path, PathBuf};
+use std::process::Output;
 use tempfile::TempDir;
 
-fn run_command(command: String, stdin: Vec<u8>, err_context: String) -> Result<Output> {
-    let mut split = command.split_ascii_whitespace().map(str::to_owned);
-    let program = split.next().ok_or(format!(
-        "invalid command string {}: could not find program name{}",
-        command, err_context
-    ))?;
-    let mut c = Command::new(program.clone());
-
-    let mut stdin_file = None;
-    let mut stdout_file = None;
+/// Runs a command on the contents of a file, given its path within its folder
+/// and the URL it will have on the site. The placeholders in the command's arguments
+/// (see the README) are replaced first, and `%%` with `%`. The contents are written to
+/// the temporary input file if `%i` is used, and to stdin otherwise. If `%o` is used,
+/// the temporary output file's contents are used as the command's stdout. Both temporary files have the file's name.
+/// If `%d` is used, the folder of files the command wrote there is returned too.
+fn run_command(
+    command: &config::CommandLine,
+    options: &config::RunOptions,
+    file: &Path,
+    url: &str,
+    contents: Vec<u8>,
+    err_context: String,
+) -> Result<(Output, Option<Folder>)> {
     let tmp_dir = TempDir::new().chain_err(|| "couldn't create temporary directory")?;
-
-    let args = split
-        .map(|x| match &*x {
-            "%i" => {
-                let f = tmp_dir.path().join("stdin");
-                stdin_file = Some(f.clone());
-                f.into()
-            }
-            "%o" => {
-                let f = tmp_dir.path().join("stdout");
-                stdout_file = Some(f.clone());
-                f.into()
-            }
-            _ => OsString::from(x),
+    let name = file.file_name().ok_or("couldn't get file name")?;
+    let stdin_file = tmp_dir.path().join("input").join(name);
+    let stdout_file = tmp_dir.path().join("output").join(name);
+    let output_dir = tmp_dir.path().join("files");
+    for dir in ["input", "output", "files"] {
+        fs::create_dir(tmp_dir.path().join(dir))
+            .chain_err(|| "couldn't create temporary directory")?;
+    }
+    let root = std::env::current_dir().chain_err(|| "could not access current directory")?;
+    let lossy = |p: Option<&std::ffi::OsStr>| p.unwrap_or_default().to_string_lossy().to_string();
+    let mut uses_stdin_file = false;
+    let mut uses_stdout_file = false;
+    let mut uses_output_dir = false;
+    let argv = command
+        .args()
+        .map_err(|e| format!("invalid command ({}){}: {}", command, err_context, e))?
+        .iter()
+        .map(|a| {
+            OsString::from(shell::substitute(a, &mut |c| match c {
+                'i' => {
+                    uses_stdin_file = true;
+                    Some(stdin_file.to_string_lossy().to_string())
+                }
+                'o' => {
+                    uses_stdout_file = true;
+                    Some(stdout_file.to_string_lossy().to_string())
+                }
+                'd' => {
+                    uses_output_dir = true;
+                    Some(output_dir.to_string_lossy().to_string())
+                }
+                'p' => Some(file.to_string_lossy().to_string()),
+                's' => Some(lossy(file.file_stem())),
+                'e' => Some(lossy(file.extension())),
+                'u' => Some(url.to_string()),
+                'r' => Some(root.to_string_lossy().to_string()),
+                _ => None,
+            }))
         })
-        .collect::<Vec<OsString>>();
-    c.args(args);
-
-    if let Some(path) = stdin_file {
-        fs::write(path, stdin.clone())
-            .chain_err(|| format!("failed to write to temporary input file"))?;
-    }
-
-    let mut child = c
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .chain_err(|| format!("failed to start {}{}", program.clone(), err_context))?;
-    let mut stdin_handle = child
-        .stdin
-        .take()
-        .chain_err(|| format!("failed to get {} stdin{}", program.clone(), err_context))?;
-    let ec = err_context.clone();
-    let p = program.clone();
-    std::thread::spawn(move || {
-        stdin_handle
-            .write_all(&stdin)
-            .chain_err(|| format!("failed to write to {} stdin{}", p, ec))
-    });
-    let mut output = child
-        .wait_with_output()
-        .chain_err(|| format!("failed to wait on {}{}", program, err_context));
-
-    if let Ok(ref mut w) = output {
-        if let Some(path) = stdout_file {
-            w.stdout = fs::read(path)
-                .chain_err(|| format!("failed to read from temporary output file"))?;
+        .collect::<Vec<_>>();
+    // the contents go either to the input file or to stdin, depending on which one it reads
+    let stdin = match uses_stdin_file {
+        true => {
+            fs::write(&stdin_file, &contents)
+                .chain_err(|| "failed to write to temporary input file")?;
+            None
         }
+        false => Some(contents),
+    };
+    let mut output = runner::run(argv, stdin, options, &err_context)?;
+    if uses_stdout_file {
+        output.stdout = fs::read(stdout_file).map_err(|e| {
+            format!(
+                "failed to read the output file (%o) of command ({}){}: {}",
+                command, err_context, e
+            )
+        })?;
     }
-    output
+    let files = match uses_output_dir {
+        true => Some(Folder::read(output_dir)?),
+        false => None,
+    };
+    Ok((output, files))
 }
 
+/// Returns the path, relative to the file's folder, that a command's `output`
+/// setting gives for a file.
+fn output_path(output: &str, file: &Path) -> Result<PathBuf> {
+    let lossy = |p: Option<&std::ffi::OsStr>| p.unwrap_or_default().to_string_lossy().to_string();
+    let path = PathBuf::from(shell::substitute(output, &mut |c| match c {
+        's' => Some(lossy(file.file_stem())),
+        'e' => Some(lossy(file.extension())),
+        _ => None,
+    }));
+    if path.file_name().is_none()
+        || !path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+    {
+        bail!(
+            "output {:?} (for {:?}) must be a relative path that stays within the file's folder",
+            output,
+            file
+        );
+    }
+    Ok(path)
+}
+
+/// Picks the template for a content file: the one named by the `template` key of its
+/// metadata if there is one, or else the closest matching template (see the README).
+/// Returns the template's path within templates/ and its contents.
+fn select_template(
+    templates_fs: &Folder,
+    filepath: &Path,
+    contents: &[u8],
+    front_matter: &FrontMatter,
+    default: &str,
+) -> Result<(PathBuf, Vec<u8>)> {
+    let mut find_filepath: PathBuf = filepath.iter().skip(1).collect();
+    let name = match front_matter.metadata.get("template") {
+        None => {
+            return match templates_fs.find(find_filepath.clone()) {
+                Some(t) => Ok(t),
+                None => {
+                    find_filepath.set_file_name(default);
+                    templates_fs.find(find_filepath.clone()).ok_or_else(|| {
+                        format!("failed to find a matching template for {:?}", filepath).into()
+                    })
+                }
+            };
+        }
+        Some(Value::String(name)) => name,
+        Some(_) => {
+            return Err(template_error(
+                filepath,
+                contents,
+                front_matter,
+                "`template` must be a string".into(),
+            ))
+        }
+    };
+    // a full path is used as-is, anything else is matched like the file's own name would be
+    let found = match Path::new(name).strip_prefix("templates") {
+        Ok(p) => templates_fs
+            .get_file(p)
+            .map(|c| (p.to_path_buf(), c.clone())),
+        Err(_) => {
+            find_filepath.set_file_name(name);
+            templates_fs.find(find_filepath)
+        }
+    };
+    found.ok_or_else(|| {
+        template_error(
+            filepath,
+            contents,
+            front_matter,
+            format!("template `{}` does not exist", name),
+        )
+    })
+}
+
+/// Creates an error pointing at the `template` key in a content file's metadata.
+fn template_error(
+    filepath: &Path,
+    contents: &[u8],
+    front_matter: &FrontMatter,
+    message: String,
+) -> Error {
+    lazy_static! {
+        // the key at the start of a line (YAML or TOML), or a quoted key (JSON)
+        static ref TEMPLATE_KEY: Regex =
+            Regex::new(r#"(?m)^[ \t]*(template)[ \t]*[:=]|"(template)"[ \t]*:"#).unwrap();
+    }
+    let text = String::from_utf8_lossy(contents);
+    let start = TEMPLATE_KEY
+        .captures(&text[..front_matter.body_start])
+        .and_then(|c| c.get(1).or_else(|| c.get(2)))
+        .map_or(0, |m| m.start());
+    let mut d = Diagnostic::new(
+        message,
+        filepath.to_path_buf(),
+        &text,
+        start,
+        start + "template".len(),
+    );
+    d.notes.push(format!(
+        "give a path starting with `templates/`, or a name to look for in {} and its parent folders",
+        Path::new("templates")
+            .join(filepath.iter().skip(1).collect::<PathBuf>().parent().unwrap_or(Path::new("")))
+            .display()
+    ));
+    ErrorKind::Diagnostics(vec![d]).into()
+}
+
+/// Wraps a template in the layouts it declares with a `$-- layout: <name>` first line,
+/// by putting it in place of `$body$` in the layout. Layouts are resolved like a
+/// `template` key, from the folder of the template naming them (skipping the template
+/// itself, so `blog/default.html` can use `layout: default`). Returns None if the template
+/// has no layout.
+fn apply_layouts(
+    templates_fs: &Folder,
+    template_path: &Path,
+    contents: &[u8],
+) -> Result<Option<Vec<u8>>> {
+    lazy_static! {
+        static ref LAYOUT: Regex =
+            Regex::new(r"\A\$--[ \t]*layout:[ \t]*(\S+)[ \t]*(\r?\n)?").unwrap();
+        static ref BODY: Regex = Regex::new(r"\$body\$|\$\{body\}").unwrap();
+    }
+    let mut path = template_path.to_path_buf();
+    let mut text = String::from_utf8(contents.to_vec()).chain_err(|| {
+        format!(
+            "template {:?} is not valid UTF-8",
+            templates_fs.path.join(&path)
+        )
+    })?;
+    let mut seen = vec![path.clone()];
+    while let Some(c) = LAYOUT.captures(&text) {
+        let name = c.get(1).map_or("", |m| m.as_str()).to_string();
+        let name_range = c.get(1).map_or(0..0, |m| m.range());
+        let diagnostic = |message: String| {
+            Error::from_kind(ErrorKind::Diagnostics(vec![Diagnostic::new(
+                message,
+                templates_fs.path.join(&path),
+                &text,
+                name_range.start,
+                name_range.end,
+            )]))
+        };
+        let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let found = match Path::new(&name).strip_prefix("templates") {
+            Ok(p) => templates_fs
+                .get_file(p)
+                .map(|c| (p.to_path_buf(), c.clone())),
+            Err(_) => match templates_fs.find(dir.join(&name)) {
+                Some((p, _)) if p == path => {
+                    dir.parent().and_then(|d| templates_fs.find(d.join(&name)))
+                }
+                found => found,
+            },
+        };
+        let (layout_path, layout) = match found {
+            Some(l) => l,
+            None => return Err(diagnostic(format!("layout `{}` does not exist", name))),
+        };
+        if seen.contains(&layout_path) {
+            let chain = seen
+                .iter()
+                .chain(std::iter::once(&layout_path))
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(diagnostic(format!("layouts form a cycle: {}", chain)));
+        }
+        let layout = String::from_utf8(layout).chain_err(|| {
+            format!(
+                "template {:?} is not valid UTF-8",
+                templates_fs.path.join(&layout_path)
+            )
+        })?;
+        let body = match BODY.find(&layout) {
+            Some(b) => b.range(),
+            None => {
+                return Err(diagnostic(format!(
+                    "layout {:?} doesn't contain `$body$`",
+                    templates_fs.path.join(&layout_path)
+                )))
+            }
+        };
+        text = [
+            &layout[..body.start],
+            &text[c.get(0).map_or(0, |m| m.end())..],
+            &layout[body.end..],
+        ]
+        .concat();
+        seen.push(layout_path.clone());
+        path = layout_path;
+    }
+    if seen.len() == 1 {
+        return Ok(None);
+    }
+    Ok(Some(text.into_bytes()))
+}
+
+/// Renders the files in contents/ with pandoc, using the config for each file
+/// (see Config::for_file).
 pub fn pandoc(
-    folder: Folder,
-    filters: Vec<config::Filter>,
-    extra_args: Vec<String>,
-    default: String,
+    folder: &Folder,
+    config: &config::Config,
+    directories: &BTreeMap<PathBuf, config::DirConfig>,
+    scripts: &Scripts,
 ) -> Result<Folder> {
-    // TODO: process snippets
-    let mut command = String::from("pandoc --to html5 --standalone ");
-    command.push_str(&extra_args.join(" "));
     let contents_fs = folder
         .folders
         .get(&OsString::from("contents"))
@@ -95,51 +323,125 @@ pub fn pandoc(
         .folders
         .get(&OsString::from("snippets"))
         .chain_err(|| "Could not find folder 'snippets'")?;
+    // templates that use snippets are expanded for each file and written next to a copy
+    // of the templates folder, so that pandoc can still find the partials they use
+    let root = std::env::current_dir().chain_err(|| "could not access current directory")?;
+    let expanded_templates = TempDir::new().chain_err(|| "couldn't create temporary directory")?;
+    templates_fs.write(expanded_templates.path().to_path_buf())?;
+    let mut expanded_count = 0;
     contents_fs
         .clone()
-        .map(PathBuf::new(), &mut |filepath, contents| {
-            let mut template = templates_fs.clone().path;
-            let mut find_filepath: PathBuf = filepath.iter().skip(2).collect();
-            template.push(match templates_fs.find(find_filepath.clone()) {
-                None => {
-                    find_filepath.set_file_name(default.clone());
-                    match templates_fs.find(find_filepath.clone()) {
-                        None => bail!("failed to find a matching template for {:?}", find_filepath),
-                        Some((fp, _)) => fp,
-                    }
-                }
-                Some((fp, _)) => fp,
-            });
+        .map(contents_fs.path.clone(), &mut |filepath, contents| {
+            let mut front_matter = FrontMatter::parse(&filepath, &contents)?;
+            let within: PathBuf = filepath.iter().skip(1).collect();
+            let file_config = config.for_file(directories, &within);
+            let site = Value::Mapping(file_config.site.clone());
+            let ctx = snippets::Context {
+                snippets: snippets_fs,
+                contents: contents_fs,
+                site: &site,
+                scripts,
+            };
+            let (template_path, original_template) = select_template(
+                templates_fs,
+                &filepath,
+                &contents,
+                &front_matter,
+                &file_config.default_template,
+            )?;
+            let mut template = templates_fs.path.join(&template_path);
+            let mut template_contents =
+                apply_layouts(templates_fs, &template_path, &original_template)?
+                    .unwrap_or_else(|| original_template.clone());
+            if template_contents
+                .windows(call::CALL_START.len())
+                .any(|w| w == call::CALL_START.as_bytes())
+            {
+                template_contents = snippets::Snippet::process_template(
+                    &ctx,
+                    template.clone(),
+                    filepath.clone(),
+                    template_contents,
+                )?;
+            }
+            // templates that were changed are written to the temporary copy
+            if template_contents != original_template {
+                expanded_count += 1;
+                let name = format!(
+                    ".span-{}-{}",
+                    expanded_count,
+                    template_path
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                );
+                template = expanded_templates
+                    .path()
+                    .join(template_path.with_file_name(name));
+                fs::write(&template, template_contents)
+                    .chain_err(|| format!("couldn't write expanded template {:?}", template))?;
+            }
             let err_context = format!(
                 ", while processing file {:?}, using template {:?}",
                 filepath,
-                template.clone(),
+                templates_fs.path.join(&template_path),
             );
-            let mut child = command.clone();
-            child.push_str(" --template ");
-            child.push_str(&template.to_string_lossy());
-
-            for filter in filters.iter() {
-                if contents_fs
-                    .get_globs(&filter.files)?
-                    .keys()
-                    .any(|e| *e == filepath)
-                {
-                    child.push_str("--filter=");
-                    child.push_str(
-                        filter
-                            .path
-                            .to_str()
-                            .ok_or(format!("couldn't get path of filter"))?,
-                    );
+            // site-wide values are passed to pandoc as metadata under `site`
+            let mut metadata = Mapping::new();
+            metadata.insert(Value::String("site".into()), site.clone());
+            let mut metadata_file = tempfile::Builder::new()
+                .suffix(".yaml")
+                .tempfile()
+                .chain_err(|| "couldn't create temporary metadata file")?;
+            serde_yaml::to_writer(&mut metadata_file, &metadata)
+                .chain_err(|| "couldn't write temporary metadata file")?;
+            let mut child: Vec<OsString> = ["pandoc", "--to", "html5", "--standalone"]
+                .iter()
+                .map(OsString::from)
+                .collect();
+            child.push("--metadata-file".into());
+            child.push(metadata_file.path().into());
+            for args in file_config.extra_args.iter() {
+                let args = shell::split(args)
+                    .chain_err(|| format!("invalid arguments {:?} in extra_args", args))?;
+                child.extend(args.into_iter().map(OsString::from));
+            }
+            child.push("--template".into());
+            // absolute, since pandoc may be run in another folder (see RunOptions::cwd)
+            child.push(root.join(&template).into());
+
+            for filter in file_config.filters.iter() {
+                if filter.matches(&within)? {
+                    let path = shell::expand(&filter.path.to_string_lossy())
+                        .chain_err(|| format!("invalid filter path {:?}", filter.path))?;
+                    child.push(format!("--filter={}", path).into());
                 }
             }
 
-            let output = run_command(
-                child,
-                snippets::Snippet::process_contents(snippets_fs, filepath.clone(), contents)?,
-                err_context.clone(),
+            let mut metadata_changed = false;
+            if file_config.snippets_in_metadata {
+                metadata_changed = snippets::Snippet::process_metadata(
+                    &ctx,
+                    filepath.clone(),
+                    &contents,
+                    &mut front_matter,
+                )?;
+            }
+            let body = snippets::Snippet::process_contents(
+                &ctx,
+                filepath.clone(),
+                &contents,
+                &front_matter,
             )?;
+            // pandoc only understands YAML front matter, so anything else is rewritten
+            let document = match front_matter.format {
+                Some(Format::Yaml) if !metadata_changed => {
+                    [&contents[..front_matter.body_start], &body].concat()
+                }
+                None => body,
+                _ => front_matter.to_yaml_document(&body)?,
+            };
+            let output = runner::run(child, Some(document), &file_config.pandoc, &err_context)?;
 
             if !output.stderr.is_empty() {
                 bail!(
@@ -149,54 +451,316 @@ pub fn pandoc(
                 )
             }
 
-            Ok(Some((
-                filepath
-                    .join(
-                        filepath
-                            .file_stem()
-                            .ok_or(format!("couldn't get file stem of {:?}", filepath))?,
-                    )
-                    .join(PathBuf::from(".html")),
-                output.stdout,
-            )))
+            Ok(Some((filepath.with_extension("html"), output.stdout)))
         })
         .chain_err(|| "failed to build")
 }
 
+/// Runs the checks of a command on its result, returning a description of
+/// the first problem found.
+fn check_output(error_on: &config::ErrorOn, output: &Output) -> Option<String> {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    error_on.0.iter().find_map(|check| match check {
+        config::Check::Stdout if !stdout.is_empty() => {
+            Some(format!("wrote to stdout:\n  {}", indent(&stdout)))
+        }
+        config::Check::Stderr if !stderr.is_empty() => {
+            Some(format!("wrote to stderr:\n  {}", indent(&stderr)))
+        }
+        config::Check::Status if !output.status.success() => Some(format!(
+            "failed ({}):\n  {}",
+            output.status,
+            indent(&stderr)
+        )),
+        config::Check::Match(re) => {
+            let lines = stdout
+                .lines()
+                .chain(stderr.lines())
+                .filter(|l| re.is_match(l))
+                .collect::<Vec<_>>();
+            if lines.is_empty() {
+                None
+            } else {
+                Some(format!(
+                    "printed lines matching `{}`:\n  {}",
+                    re.as_str(),
+                    lines.join("\n  ")
+                ))
+            }
+        }
+        _ => None,
+    })
+}
+
+/// Indents every line of a command's output but the first, which follows a message.
+fn indent(output: &str) -> String {
+    output.trim_end().replace('\n', "\n  ")
+}
+
+/// Whether a content file is marked `draft: true` in its front matter.
+/// Front matter errors are reported later, when the file is built.
+fn is_draft(filepath: &Path, contents: &[u8]) -> bool {
+    FrontMatter::parse(filepath, contents)
+        .is_ok_and(|f| f.metadata.get("draft") == Some(&Value::Bool(true)))
+}
+
+/// Builds the site. Globs in the config are matched against paths within contents/,
+/// and the result is the built contents/ folder.
 pub fn build(folder: Folder, config: config::Config) -> Result<Folder> {
     let mut f = folder;
-    f = f.remove_globs(&config.ignore)?;
-    for pr in config.pre_run {
-        f = f.map_globs(
+    let mut contents = f
+        .folders
+        .remove(&OsString::from("contents"))
+        .chain_err(|| "Could not find folder 'contents'")?;
+    let directories = config::load_directories(&contents)?;
+    contents = contents.remove_globs(&vec![format!("**/{}", config::DIR_FILE_NAME)])?;
+    contents = contents.remove_globs(&config.ignore)?;
+    contents = contents.clone().map(contents.path.clone(), &mut |fp, c| {
+        let within: PathBuf = fp.iter().skip(1).collect();
+        if !config.for_file(&directories, &within).drafts && is_draft(&fp, &c) {
+            Ok(None)
+        } else {
+            Ok(Some((fp, c)))
+        }
+    })?;
+    // files that aren't passed through are rendered to .html, which changes their URL
+    let passed_through = contents
+        .get_globs(&config.passthrough)?
+        .into_keys()
+        .collect();
+    let stages = config.stages.iter().map(stage).collect::<Vec<_>>();
+    let root = std::env::current_dir().chain_err(|| "could not access current directory")?;
+    let scripts = Scripts::load(&config, &root)?;
+    let mut b = Build {
+        output: Folder::new(contents.path.clone()),
+        contents,
+        source: f,
+        directories,
+        passed_through,
+        rendered: false,
+        cache: Cache::default(),
+        scripts,
+        config,
+    };
+    for s in stages {
+        s.run(&mut b)?;
+    }
+    plugins::run(Hook::SiteFinalize, &b.config, &b.directories, b.output)
+}
+
+/// Returns the stage that a stage in the config stands for.
+fn stage(config: &config::StageConfig) -> Box<dyn Stage> {
+    use config::StageConfig as S;
+    match config.clone() {
+        S::PreRun => Box::new(PreRun),
+        S::Passthrough => Box::new(Passthrough),
+        S::Pandoc => Box::new(Pandoc),
+        S::PostRun => Box::new(PostRun),
+        S::Minify { files } => Box::new(stages::Minify { files }),
+        S::Copy { files, to } => Box::new(stages::Copy {
+            files,
+            to,
+            keep: true,
+        }),
+        S::Rename { files, to } => Box::new(stages::Copy {
+            files,
+            to,
+            keep: false,
+        }),
+        S::FrontmatterInject {
+            files,
+            values,
+            overwrite,
+        } => Box::new(stages::FrontmatterInject {
+            files,
+            values,
+            overwrite,
+        }),
+    }
+}
+
+/// Runs the commands in pre_run on contents/.
+struct PreRun;
+
+impl Stage for PreRun {
+    fn run(&self, b: &mut Build) -> Result<()> {
+        let contents = std::mem::replace(&mut b.contents, Folder::new(PathBuf::new()));
+        let root = contents.path.clone();
+        let passed_through = &b.passed_through;
+        let (contents, written) = run_commands(
+            contents,
+            &b.config.pre_run,
+            "pre-run",
+            &|within| {
+                if passed_through.contains(&PathBuf::from("contents").join(within)) {
+                    within.to_path_buf()
+                } else {
+                    within.with_extension("html")
+                }
+            },
+            &mut b.cache,
+        )?;
+        b.contents = contents;
+        b.passed_through
+            .extend(written.into_iter().map(|p| root.join(p)));
+        Ok(())
+    }
+}
+
+/// Moves the files that match passthrough, and those pre-run commands wrote, from
+/// contents/ to the built site.
+struct Passthrough;
+
+impl Stage for Passthrough {
+    fn run(&self, b: &mut Build) -> Result<()> {
+        let root = b.contents.path.clone();
+        let passed_through = &b.passed_through;
+        let written = b.contents.clone().map(root.clone(), &mut |fp, c| {
+            Ok(passed_through.contains(&fp).then_some((fp, c)))
+        })?;
+        let pass = Folder::join(b.contents.filter_globs(&b.config.passthrough)?, written)?;
+        b.contents = b
+            .contents
+            .remove_globs(&b.config.passthrough)?
+            .map(root, &mut |fp, c| {
+                Ok((!passed_through.contains(&fp)).then_some((fp, c)))
+            })?;
+        let output = std::mem::replace(&mut b.output, Folder::new(PathBuf::new()));
+        b.output = Folder::join(output, pass)?;
+        Ok(())
+    }
+}
+
+/// Renders what's left in contents/ into the built site.
+struct Pandoc;
+
+impl Stage for Pandoc {
+    fn run(&self, b: &mut Build) -> Result<()> {
+        if let Some(data_fs) = b.source.folders.get(&OsString::from("data")) {
+            let mut data = Mapping::new();
+            data.insert(
+                Value::String("data".into()),
+                Value::Mapping(data::load(data_fs).chain_err(|| "failed to load data files")?),
+            );
+            b.config.merge_site(data);
+        }
+        let contents = std::mem::replace(&mut b.contents, Folder::new(b.output.path.clone()));
+        let contents = b.scripts.transform(contents)?;
+        let contents = plugins::run(Hook::PreRender, &b.config, &b.directories, contents)?;
+        b.source
+            .folders
+            .insert(OsString::from("contents"), contents);
+        let rendered = pandoc(&b.source, &b.config, &b.directories, &b.scripts)?;
+        b.source.folders.remove(&OsString::from("contents"));
+        let output = std::mem::replace(&mut b.output, Folder::new(PathBuf::new()));
+        let output = Folder::join(output, rendered)?;
+        b.output = plugins::run(Hook::PostRender, &b.config, &b.directories, output)?;
+        b.rendered = true;
+        Ok(())
+    }
+}
+
+/// Runs the commands in post_run on the built site.
+struct PostRun;
+
+impl Stage for PostRun {
+    fn run(&self, b: &mut Build) -> Result<()> {
+        let output = std::mem::replace(&mut b.output, Folder::new(PathBuf::new()));
+        (b.output, _) = run_commands(
+            output,
+            &b.config.post_run,
+            "post-run",
+            &|within| within.to_path_buf(),
+            &mut b.cache,
+        )?;
+        Ok(())
+    }
+}
+
+/// Runs each of the commands, in order, on the files of a folder that match its globs,
+/// given the path on the site that a file's path within the folder ends up at. Files
+/// that a command writes elsewhere are added once it has processed every file, and
+/// returned too, unless the command has render_outputs set.
+fn run_commands(
+    mut folder: Folder,
+    commands: &[config::PreRun],
+    stage: &str,
+    site_path: &dyn Fn(&Path) -> PathBuf,
+    cache: &mut Cache,
+) -> Result<(Folder, Vec<PathBuf>)> {
+    let mut written = Vec::new();
+    for pr in commands.iter() {
+        // files written to another path, added once all files have been processed
+        let mut produced = Vec::new();
+        let site = match pr.builtin {
+            Some(_) => folder
+                .get_globs(&vec!["**".into()])?
+                .into_keys()
+                .map(|p| site_path(&p.iter().skip(1).collect::<PathBuf>()))
+                .collect(),
+            None => HashSet::new(),
+        };
+        folder = folder.map_globs(
             &pr.files,
             &mut |fp, c| {
                 let err_context = format!(", while processing file {:?}", fp);
-                let output = run_command(pr.command.clone(), c.clone(), err_context.clone())?;
-                if pr.error_on != "none" {
-                    if pr.error_on == "stderr" && !output.stderr.is_empty() {
-                        bail!(
-                            "error from pre-run command ({}) {}:\n  {}",
-                            pr.command,
-                            err_context,
-                            String::from_utf8_lossy(&output.stderr),
-                        )
-                    } else if pr.error_on == "stdout" && !output.stdout.is_empty() {
-                        bail!(
-                            "error from pre-run command ({}) {}:\n  {}",
-                            pr.command,
-                            err_context,
-                            String::from_utf8_lossy(&output.stdout),
-                        )
-                    } else if pr.error_on == "status" && output.status.success() {
-                        bail!(
-                            "error from pre-run command ({}) {}:\n  {}",
-                            pr.command,
-                            err_context,
-                            String::from_utf8_lossy(&output.stdout),
-                        )
+                let within: PathBuf = fp.iter().skip(1).collect();
+                let (output, files) = match (&pr.command, pr.builtin) {
+                    (Some(command), _) => {
+                        let url = site_path(&within);
+                        let url = format!("/{}", url.to_string_lossy().replace('\\', "/"));
+                        let key = match pr.cache {
+                            true => Some(cache.key(pr, command, &within, &url, &c)?),
+                            false => None,
+                        };
+                        match key.as_deref().map(|k| cache.get(k)).transpose()?.flatten() {
+                            Some(result) => result,
+                            None => {
+                                let (output, files) = run_command(
+                                    command,
+                                    &pr.run_options(),
+                                    &within,
+                                    &url,
+                                    c.clone(),
+                                    err_context.clone(),
+                                )?;
+                                if let Some(ref k) = key {
+                                    if output.status.success() {
+                                        cache.put(k, &output, &files)?;
+                                    }
+                                }
+                                (output, files)
+                            }
+                        }
                     }
+                    (None, Some(b)) => (
+                        builtins::run(b, &site_path(&within), c.clone(), &site),
+                        None,
+                    ),
+                    (None, None) => bail!("{} {} has nothing to run", stage, pr),
+                };
+                if let Some(problem) = check_output(&pr.error_on, &output) {
+                    let message = format!("{} {}{}, {}", stage, pr, err_context, problem);
+                    match pr.level {
+                        config::Level::Error => bail!(message),
+                        config::Level::Warning => eprintln!("warning: {}", message),
+                    }
+                }
+                let dir = within.parent().unwrap_or(Path::new("")).to_path_buf();
+                let moved = pr.output.is_some() || files.is_some();
+                if let Some(ref o) = pr.output {
+                    produced.push((dir.join(output_path(o, &within)?), output.stdout.clone()));
+                }
+                if let Some(f) = files {
+                    f.map(PathBuf::new(), &mut |p, f| {
+                        produced.push((dir.join(&p), f.clone()));
+                        Ok(Some((p, f)))
+                    })?;
                 }
-                if pr.replace {
+                if moved {
+                    Ok(if pr.keep_source { Some((fp, c)) } else { None })
+                } else if pr.replace {
                     Ok(Some((fp, output.stdout)))
                 } else {
                     Ok(Some((fp, c)))
@@ -204,15 +768,162 @@ pub fn build(folder: Folder, config: config::Config) -> Result<Folder> {
             },
             &mut |fp, c| Ok(Some((fp, c))),
         )?;
+        for (p, c) in produced {
+            if !pr.render_outputs {
+                written.push(p.clone());
+            }
+            folder.push(p, c)?;
+        }
+    }
+    Ok((folder, written))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn templates(files: &[(&str, &str)]) -> Folder {
+        let mut folder = Folder::new(PathBuf::from("templates"));
+        for (path, text) in files {
+            folder
+                .push(PathBuf::from(path), text.as_bytes().to_vec())
+                .unwrap();
+        }
+        folder
+    }
+
+    fn layouts(folder: &Folder, path: &str) -> Result<Option<String>> {
+        let contents = folder.get_file(Path::new(path)).unwrap();
+        Ok(
+            apply_layouts(folder, Path::new(path), contents)?
+                .map(|t| String::from_utf8(t).unwrap()),
+        )
+    }
+
+    fn layouts_error(folder: &Folder, path: &str) -> String {
+        layouts(folder, path).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn templates_without_layouts_are_left_alone() {
+        let t = templates(&[("default.html", "$body$\n")]);
+        assert_eq!(layouts(&t, "default.html").unwrap(), None);
+    }
+
+    #[test]
+    fn chains_layouts() {
+        let t = templates(&[
+            ("base.html", "<html>$body$</html>"),
+            ("blog/post.html", "$-- layout: article\n<p>$body$</p>"),
+            (
+                "blog/article.html",
+                "$-- layout: base\r\n<article>${body}</article>",
+            ),
+        ]);
+        assert_eq!(
+            layouts(&t, "blog/post.html").unwrap().unwrap(),
+            "<html><article><p>$body$</p></article></html>"
+        );
+    }
+
+    #[test]
+    fn skips_the_template_itself() {
+        let t = templates(&[
+            ("default.html", "<html>$body$</html>"),
+            (
+                "blog/default.html",
+                "$-- layout: default\n<main>$body$</main>",
+            ),
+        ]);
+        assert_eq!(
+            layouts(&t, "blog/default.html").unwrap().unwrap(),
+            "<html><main>$body$</main></html>"
+        );
+    }
+
+    #[test]
+    fn resolves_paths_from_templates() {
+        let t = templates(&[
+            ("wrap.html", "<div>$body$</div>"),
+            ("blog/wrap.html", "<section>$body$</section>"),
+            ("page.html", "$-- layout: templates/blog/wrap.html\n$body$"),
+        ]);
+        assert_eq!(
+            layouts(&t, "page.html").unwrap().unwrap(),
+            "<section>$body$</section>"
+        );
+        let t = templates(&[("page.html", "$-- layout: templates/wrap.html\n$body$")]);
+        assert!(
+            layouts_error(&t, "page.html").contains("layout `templates/wrap.html` does not exist")
+        );
+    }
+
+    #[test]
+    fn reports_cycles() {
+        let t = templates(&[
+            ("a.html", "$-- layout: b\n$body$"),
+            ("b.html", "$-- layout: c\n$body$"),
+            ("c.html", "$-- layout: a\n$body$"),
+        ]);
+        let e = layouts_error(&t, "a.html");
+        assert!(
+            e.contains("layouts form a cycle: a.html -> b.html -> c.html -> a.html"),
+            "{}",
+            e
+        );
+        assert!(e.contains("templates/c.html:1:13"), "{}", e);
+    }
+
+    #[test]
+    fn reports_layouts_without_a_body() {
+        let t = templates(&[
+            ("base.html", "<html></html>"),
+            ("page.html", "$-- layout: base\n$body$"),
+        ]);
+        let e = layouts_error(&t, "page.html");
+        assert!(
+            e.contains("layout \"templates/base.html\" doesn't contain `$body$`"),
+            "{}",
+            e
+        );
+        assert!(e.contains("templates/page.html:1:13"), "{}", e);
+    }
+
+    #[cfg(unix)]
+    fn output(code: i32, stdout: &str, stderr: &str) -> Output {
+        use std::os::unix::process::ExitStatusExt;
+        Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: stdout.into(),
+            stderr: stderr.into(),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn checks_output() {
+        let error_on = |yaml: &str| {
+            config::ErrorOn::try_from(serde_yaml::from_str::<Value>(yaml).unwrap()).unwrap()
+        };
+        let status = error_on("status");
+        assert_eq!(check_output(&status, &output(0, "out", "warning")), None);
+        assert_eq!(
+            check_output(&status, &output(2, "", "bad\nworse")).unwrap(),
+            "failed (exit status: 2):\n  bad\n  worse"
+        );
+        assert_eq!(
+            check_output(&error_on("none"), &output(2, "out", "err")),
+            None
+        );
+        let streams = error_on("[stdout, stderr]");
+        assert_eq!(check_output(&streams, &output(1, "", "")), None);
+        assert!(check_output(&streams, &output(0, "", "err"))
+            .unwrap()
+            .starts_with("wrote to stderr"));
+        let matches = error_on("{match: '^E[0-9]'}");
+        assert_eq!(check_output(&matches, &output(1, "ok\nW1", "")), None);
+        assert!(check_output(&matches, &output(0, "ok", "E1 broken"))
+            .unwrap()
+            .contains("E1 broken"));
     }
-    let pass = f.filter_globs(&config.passthrough)?;
-    f = f.remove_globs(&config.passthrough)?;
-    f = pandoc(
-        f,
-        config.filters,
-        config.extra_args,
-        config.default_template,
-    )?;
-    f = Folder::join(pass, f)?;
-    Ok(f)
 }
@@ -0,0 +1,265 @@
+use crate::config::Builtin;
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::process::{ExitStatus, Output};
+
+/// Runs a builtin on a file of the built site, given its path within the site and the
+/// paths of all the site's files. The result looks like the output of a command that
+/// succeeded, so that error_on and replace work the same way for both.
+pub fn run(builtin: Builtin, file: &Path, contents: Vec<u8>, site: &HashSet<PathBuf>) -> Output {
+    let (stdout, stderr) = match builtin {
+        Builtin::MinifyHtml => (minify_html(&contents), Vec::new()),
+        Builtin::CheckLinks => {
+            let stderr = check_links(file, &contents, site);
+            (contents, stderr)
+        }
+    };
+    Output {
+        status: ExitStatus::default(),
+        stdout,
+        stderr,
+    }
+}
+
+/// Removes comments (but not conditional comments) and collapses runs of whitespace
+/// into a single space, leaving elements whose whitespace matters alone.
+pub fn minify_html(html: &[u8]) -> Vec<u8> {
+    lazy_static! {
+        static ref KEEP: Regex = Regex::new(
+            r"(?is)<pre\b.*?</pre\s*>|<textarea\b.*?</textarea\s*>|<script\b.*?</script\s*>|<style\b.*?</style\s*>"
+        )
+        .unwrap();
+        static ref COMMENT: Regex = Regex::new(r"(?s)<!--(?:[^\[].*?)?-->").unwrap();
+        static ref SPACE: Regex = Regex::new(r"\s+").unwrap();
+    }
+    let minify = |s: &[u8]| {
+        let s = COMMENT.replace_all(s, &b""[..]);
+        SPACE.replace_all(&s, &b" "[..]).into_owned()
+    };
+    let mut res = Vec::new();
+    let mut last = 0;
+    for m in KEEP.find_iter(html) {
+        res.extend(minify(&html[last..m.start()]));
+        res.extend_from_slice(m.as_bytes());
+        last = m.end();
+    }
+    res.extend(minify(&html[last..]));
+    res
+}
+
+/// Removes comments from CSS and collapses runs of whitespace into a single space,
+/// dropping it next to `{`, `}` and `;`. Strings are left alone.
+pub fn minify_css(css: &[u8]) -> Vec<u8> {
+    let mut res: Vec<u8> = Vec::new();
+    let mut i = 0;
+    let mut space = false;
+    while i < css.len() {
+        let c = css[i];
+        if c.is_ascii_whitespace() {
+            space = true;
+            i += 1;
+            continue;
+        }
+        if c == b'/' && css.get(i + 1) == Some(&b'*') {
+            i = match css[i + 2..].windows(2).position(|w| w == b"*/") {
+                Some(end) => i + 2 + end + 2,
+                None => css.len(),
+            };
+            continue;
+        }
+        if space
+            && !res.is_empty()
+            && !matches!(res.last(), Some(b'{' | b'}' | b';'))
+            && !matches!(c, b'{' | b'}' | b';')
+        {
+            res.push(b' ');
+        }
+        space = false;
+        if c == b'"' || c == b'\'' {
+            // copy the string up to its closing quote, skipping escaped characters
+            let start = i;
+            i += 1;
+            while i < css.len() && css[i] != c {
+                i += if css[i] == b'\\' { 2 } else { 1 };
+            }
+            i = (i + 1).min(css.len());
+            res.extend_from_slice(&css[start..i]);
+            continue;
+        }
+        res.push(c);
+        i += 1;
+    }
+    res
+}
+
+/// Returns a line for each `href` or `src` in the HTML that points to a file that isn't
+/// part of the site. Links to other sites, and `mailto:` and the like, aren't checked.
+fn check_links(file: &Path, html: &[u8], site: &HashSet<PathBuf>) -> Vec<u8> {
+    lazy_static! {
+        static ref LINK: Regex =
+            Regex::new(r#"(?i)\b(?:href|src)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap();
+        static ref EXTERNAL: Regex = Regex::new(r"^(?:[a-zA-Z][a-zA-Z0-9+.-]*:|//)").unwrap();
+    }
+    let dir = file.parent().unwrap_or(Path::new(""));
+    let mut res = Vec::new();
+    for c in LINK.captures_iter(html) {
+        let link = match c.get(1).or_else(|| c.get(2)) {
+            Some(l) => String::from_utf8_lossy(l.as_bytes()).to_string(),
+            None => continue,
+        };
+        if EXTERNAL.is_match(link.as_bytes()) {
+            continue;
+        }
+        let path = percent_decode(link.split(['#', '?']).next().unwrap_or_default());
+        if path.is_empty() {
+            continue;
+        }
+        let path = path.as_str();
+        let target = match path.strip_prefix('/') {
+            Some(p) => resolve(Path::new(""), p),
+            None => resolve(dir, path),
+        };
+        let found = match target {
+            Some(t) if path.ends_with('/') => site.contains(&t.join("index.html")),
+            Some(t) => site.contains(&t) || site.contains(&t.join("index.html")),
+            None => false,
+        };
+        if !found {
+            res.extend(format!("broken link to {} in {}\n", link, file.display()).as_bytes());
+        }
+    }
+    res
+}
+
+/// Decodes the `%XX` escapes in a URL path, like `%20` for a space. A `%` that isn't
+/// followed by two hex digits is kept as it is.
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut res = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match hex.map(|h| u8::from_str_radix(h, 16)) {
+            Some(Ok(b)) if bytes[i] == b'%' => {
+                res.push(b);
+                i += 3;
+            }
+            _ => {
+                res.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&res).to_string()
+}
+
+/// Joins a URL path onto a folder of the site, returning None if it leaves the site.
+fn resolve(dir: &Path, path: &str) -> Option<PathBuf> {
+    let mut res = dir.to_path_buf();
+    for c in Path::new(path).components() {
+        match c {
+            Component::Normal(n) => res.push(n),
+            Component::ParentDir if !res.pop() => return None,
+            _ => {}
+        }
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn html(s: &str) -> String {
+        String::from_utf8(minify_html(s.as_bytes())).unwrap()
+    }
+
+    fn css(s: &str) -> String {
+        String::from_utf8(minify_css(s.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn minifies_html() {
+        assert_eq!(
+            html("<p>\n  a   <!-- note -->b\t</p>\n\n<p>c</p>"),
+            "<p> a b </p> <p>c</p>"
+        );
+        assert_eq!(
+            html("<!--[if IE]><p>old</p><![endif]-->  <!---->x"),
+            "<!--[if IE]><p>old</p><![endif]--> x"
+        );
+        let kept = "<PRE class=x>a\n  b <!-- c --></pre >\n\n<script>\nlet a  = 1;\n</script>";
+        assert_eq!(html(kept), kept.replace(">\n\n<", "> <"));
+        assert_eq!(
+            html("<textarea>\n a</textarea> \n<style>p  { }</style>"),
+            "<textarea>\n a</textarea> <style>p  { }</style>"
+        );
+    }
+
+    #[test]
+    fn minifies_css() {
+        assert_eq!(
+            css("a , b {\n  color : red ;\n  /* note */ margin: 0 auto;\n}\n\nc{}"),
+            "a , b{color : red;margin: 0 auto;}c{}"
+        );
+        assert_eq!(
+            css("a::before { content: \"  /* x */ \\\"  \"; }"),
+            "a::before{content: \"  /* x */ \\\"  \";}"
+        );
+        assert_eq!(css("p { font: 'a  b' }/* open"), "p{font: 'a  b'}");
+    }
+
+    #[test]
+    fn resolves_paths() {
+        let r = |dir: &str, path: &str| resolve(Path::new(dir), path);
+        assert_eq!(r("blog", "post.html"), Some("blog/post.html".into()));
+        assert_eq!(r("blog", "./../css/a.css"), Some("css/a.css".into()));
+        assert_eq!(
+            r("blog/2024", "../../index.html"),
+            Some("index.html".into())
+        );
+        assert_eq!(r("blog", "../../index.html"), None);
+        assert_eq!(r("", "a//b/"), Some("a/b".into()));
+    }
+
+    #[test]
+    fn checks_links() {
+        let site: HashSet<PathBuf> = ["index.html", "blog/my file.html", "docs/index.html"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let check = |html: &str| {
+            String::from_utf8(check_links(
+                Path::new("blog/post.html"),
+                html.as_bytes(),
+                &site,
+            ))
+            .unwrap()
+        };
+        assert_eq!(
+            check(
+                "<a href=\"my%20file.html#top\">a</a> <a href='/docs/'>b</a> \
+                 <a href=\"../docs?x=1\">c</a> <a href=\"https://x.org/y\">d</a> \
+                 <a href=\"mailto:a@b.c\">e</a> <a href=\"#top\">f</a>"
+            ),
+            ""
+        );
+        assert_eq!(
+            check("<img src=\"my%2xfile.png\"> <a href=\"../../x.html\">"),
+            "broken link to my%2xfile.png in blog/post.html\n\
+             broken link to ../../x.html in blog/post.html\n"
+        );
+    }
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("my%20file%2Ehtml"), "my file.html");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+}
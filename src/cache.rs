@@ -0,0 +1,157 @@
+use crate::config::{CommandLine, PreRun, RunOptions};
+use crate::errors::*;
+use crate::runner;
+use crate::vfs::Folder;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Output};
+
+/// The results of commands run with `cache: true`, stored in the user's cache folder
+/// (see dir()) and keyed by everything that can change them.
+#[derive(Default)]
+pub struct Cache {
+    /// What each version command printed, so that it's run once per build.
+    versions: HashMap<String, Vec<u8>>,
+}
+
+/// What a command printed and the files it wrote to `%d`. Only the results of commands
+/// that succeeded are stored, so the status isn't.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Entry {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    files: Option<Folder>,
+}
+
+/// Returns the folder the cache is stored in: `$XDG_CACHE_HOME/span`,
+/// or `~/.cache/span` if that isn't set.
+pub fn dir() -> Result<PathBuf> {
+    match std::env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()) {
+        Some(d) => Ok(PathBuf::from(d).join("span")),
+        None => std::env::var_os("HOME")
+            .map(|h| PathBuf::from(h).join(".cache").join("span"))
+            .chain_err(|| {
+                "can't find the cache folder because neither XDG_CACHE_HOME nor HOME is set"
+            }),
+    }
+}
+
+/// Deletes every cached result. Returns the folder that was deleted, if there was one.
+pub fn clean() -> Result<Option<PathBuf>> {
+    let dir = dir()?;
+    if !dir.exists() {
+        return Ok(None);
+    }
+    fs::remove_dir_all(&dir).chain_err(|| format!("could not delete the cache at {:?}", dir))?;
+    Ok(Some(dir))
+}
+
+impl Cache {
+    /// Returns the key for running a command on a file: a hash of the command, what its
+    /// version command printed, how it's run, where the file is and what's in it.
+    pub fn key(
+        &mut self,
+        pr: &PreRun,
+        command: &CommandLine,
+        file: &Path,
+        url: &str,
+        contents: &[u8],
+    ) -> Result<String> {
+        let version = self.version(pr, command)?;
+        let root = std::env::current_dir().chain_err(|| "could not access current directory")?;
+        let options = serde_yaml::to_string(&pr.run_options())
+            .chain_err(|| "couldn't serialize command options")?;
+        let mut hasher = Sha256::new();
+        for part in [
+            command.to_string().as_bytes(),
+            &version,
+            options.as_bytes(),
+            root.to_string_lossy().as_bytes(),
+            file.to_string_lossy().as_bytes(),
+            url.as_bytes(),
+            contents,
+        ] {
+            // the length keeps parts from running into each other
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part);
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Returns the stored result for a key, if there is one. A result that can't be
+    /// read is treated as missing, since it will just be replaced.
+    pub fn get(&self, key: &str) -> Result<Option<(Output, Option<Folder>)>> {
+        let path = dir()?.join(&key[..2]).join(&key[2..]);
+        let entry: Entry = match fs::read(path)
+            .ok()
+            .and_then(|b| bincode::deserialize(&b).ok())
+        {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        let output = Output {
+            status: ExitStatus::default(),
+            stdout: entry.stdout,
+            stderr: entry.stderr,
+        };
+        Ok(Some((output, entry.files)))
+    }
+
+    /// Stores the result of a command that succeeded.
+    pub fn put(&self, key: &str, output: &Output, files: &Option<Folder>) -> Result<()> {
+        let folder = dir()?.join(&key[..2]);
+        fs::create_dir_all(&folder)
+            .chain_err(|| format!("could not create cache folder {:?}", folder))?;
+        let entry = bincode::serialize(&Entry {
+            stdout: output.stdout.clone(),
+            stderr: output.stderr.clone(),
+            files: files.clone(),
+        })
+        .chain_err(|| "couldn't serialize command result")?;
+        // written next to its final path and then moved, so a result is never half-written
+        let mut tmp = tempfile::NamedTempFile::new_in(&folder)
+            .chain_err(|| format!("could not write to cache folder {:?}", folder))?;
+        std::io::Write::write_all(&mut tmp, &entry)
+            .chain_err(|| format!("could not write to cache folder {:?}", folder))?;
+        tmp.persist(folder.join(&key[2..]))
+            .chain_err(|| format!("could not write to cache folder {:?}", folder))?;
+        Ok(())
+    }
+
+    /// Runs the version command of a command (`<program> --version` unless the
+    /// config says otherwise), once per build, and returns what it printed.
+    fn version(&mut self, pr: &PreRun, command: &CommandLine) -> Result<Vec<u8>> {
+        let probe = match pr.version {
+            Some(ref v) => v.args()?,
+            None => {
+                let program = command.args()?.into_iter().next().unwrap_or_default();
+                vec![program, "--version".into()]
+            }
+        };
+        let key = probe.join("\0");
+        if let Some(v) = self.versions.get(&key) {
+            return Ok(v.clone());
+        }
+        let err_context = format!(
+            ", while getting its version to cache the results of command ({}); set `version` to a command that prints it",
+            command
+        );
+        let options = RunOptions {
+            env: pr.env.clone(),
+            cwd: pr.cwd.clone(),
+            ..RunOptions::default()
+        };
+        let output = runner::run(
+            probe.into_iter().map(OsString::from).collect(),
+            None,
+            &options,
+            &err_context,
+        )?;
+        let version = [output.stdout, output.stderr].concat();
+        self.versions.insert(key, version.clone());
+        Ok(version)
+    }
+}
@@ -0,0 +1,551 @@
+use serde_yaml::{Mapping, Number, Value};
+use std::ops::Range;
+
+/// Marker that starts a snippet call.
+pub const CALL_START: &str = "$%%{";
+
+/// A snippet call found in a source file, such as
+/// `$%%{path/to/folder:snippet(val1: x, val2: [1, 2])}`.
+#[derive(Clone, Debug)]
+pub struct Call {
+    /// Byte offset of the opening `$`.
+    pub start: usize,
+    /// Byte offset just past the closing `}`.
+    pub end: usize,
+    pub metadata_path: Option<String>,
+    pub name: String,
+    pub arguments: Mapping,
+}
+
+/// A malformed snippet call.
+#[derive(Clone, Debug)]
+pub struct ParseError {
+    /// Byte offset in the source at which parsing failed.
+    pub offset: usize,
+    pub message: String,
+}
+
+/// Something in a source file that snippet expansion has to act on.
+#[derive(Clone, Debug)]
+pub enum Found {
+    Call(Call),
+    /// A marker escaped with a backslash (`\$%%{`). Holds the byte offset of the
+    /// backslash, which is dropped so that the marker is output literally.
+    Escape(usize),
+    Malformed(ParseError),
+}
+
+/// Finds and parses every snippet call in the text, in order of appearance,
+/// ignoring any that start inside the skipped byte ranges (which must be sorted).
+/// A malformed call is reported as an error and scanning resumes after its
+/// opening marker.
+pub fn find_calls(text: &str, skip: &[Range<usize>]) -> Vec<Found> {
+    let mut res = Vec::new();
+    let mut from = 0;
+    let mut skip = skip.iter().peekable();
+    while let Some(i) = text[from..].find(CALL_START) {
+        let start = from + i;
+        while skip.peek().is_some_and(|r| r.end <= start) {
+            skip.next();
+        }
+        if let Some(r) = skip.peek() {
+            if r.contains(&start) {
+                from = r.end;
+                continue;
+            }
+        }
+        // an odd number of backslashes means the marker itself is escaped
+        let backslashes = text[..start].len() - text[..start].trim_end_matches('\\').len();
+        if backslashes % 2 == 1 {
+            res.push(Found::Escape(start - 1));
+            from = start + CALL_START.len();
+            continue;
+        }
+        let mut p = Parser {
+            text,
+            pos: start + CALL_START.len(),
+        };
+        match p.call(start) {
+            Ok(call) => {
+                from = call.end;
+                res.push(Found::Call(call));
+            }
+            Err(e) => {
+                from = start + CALL_START.len();
+                res.push(Found::Malformed(e));
+            }
+        }
+    }
+    res
+}
+
+/// Returns the byte ranges of Markdown code in the text: fenced code blocks
+/// (using ``` or ~~~) and inline code spans. Indented code blocks are not detected.
+pub fn code_ranges(text: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    // the fence character, its length and the offset of the opening fence
+    let mut fence: Option<(char, usize, usize)> = None;
+    let mut inline_from = 0;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start_matches(' ');
+        let indented = line.len() - trimmed.len() > 3;
+        let run = |c: char| trimmed.chars().take_while(|x| *x == c).count();
+        match fence {
+            None => {
+                if let Some(c @ ('`' | '~')) = trimmed.chars().next() {
+                    let n = run(c);
+                    // info strings of backtick fences can't contain backticks
+                    if !indented && n >= 3 && !(c == '`' && trimmed[n..].contains('`')) {
+                        ranges.extend(inline_code_ranges(text, inline_from, offset));
+                        fence = Some((c, n, offset));
+                    }
+                }
+            }
+            Some((c, n, start)) => {
+                if !indented && run(c) >= n && trimmed.trim_start_matches(c).trim().is_empty() {
+                    ranges.push(start..offset + line.len());
+                    inline_from = offset + line.len();
+                    fence = None;
+                }
+            }
+        }
+        offset += line.len();
+    }
+    match fence {
+        Some((_, _, start)) => ranges.push(start..text.len()),
+        None => ranges.extend(inline_code_ranges(text, inline_from, text.len())),
+    }
+    ranges
+}
+
+/// Returns the byte ranges of inline code spans in text[from..to].
+/// A code span can't continue past a blank line.
+fn inline_code_ranges(text: &str, from: usize, to: usize) -> Vec<Range<usize>> {
+    let bytes = text.as_bytes();
+    let run = |i: usize| bytes[i..to].iter().take_while(|b| **b == b'`').count();
+    let mut ranges = Vec::new();
+    let mut i = from;
+    while i < to {
+        if bytes[i] != b'`' || (i > from && bytes[i - 1] == b'\\') {
+            i += 1;
+            continue;
+        }
+        let n = run(i);
+        let paragraph_end = text[i..to].find("\n\n").map_or(to, |p| i + p);
+        let mut j = i + n;
+        let mut close = None;
+        while j < paragraph_end {
+            if bytes[j] == b'`' {
+                let m = run(j);
+                if m == n {
+                    close = Some(j);
+                    break;
+                }
+                j += m;
+            } else {
+                j += 1;
+            }
+        }
+        match close {
+            Some(j) => {
+                ranges.push(i..j + n);
+                i = j + n;
+            }
+            None => i += n,
+        }
+    }
+    ranges
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn call(&mut self, start: usize) -> Result<Call, ParseError> {
+        self.skip_whitespace();
+        let head_start = self.pos;
+        let head_len = self
+            .rest()
+            .find(['(', '}', '\n'])
+            .unwrap_or(self.rest().len());
+        if !self.rest()[head_len..].starts_with('(') {
+            return Err(self.error_at(head_start + head_len, "expected `(` after snippet name"));
+        }
+        let head = &self.text[head_start..head_start + head_len];
+        let (metadata_path, name) = match head.rfind(':') {
+            Some(i) => (Some(head[..i].trim().to_string()), &head[i + 1..]),
+            None => (None, head),
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(self.error_at(head_start, "snippet is missing name"));
+        }
+        if let Some(ref p) = metadata_path {
+            if p.is_empty() {
+                return Err(self.error_at(head_start, "metadata path before `:` is empty"));
+            }
+        }
+        if let Some(i) = name.find(char::is_whitespace) {
+            let offset = head_start + head.find(name).unwrap_or(0) + i;
+            return Err(self.error_at(offset, "snippet name cannot contain whitespace"));
+        }
+        self.pos = head_start + head_len + 1;
+        let arguments = self.mapping(')')?;
+        self.skip_whitespace();
+        self.expect('}')?;
+        Ok(Call {
+            start,
+            end: self.pos,
+            metadata_path,
+            name: name.to_string(),
+            arguments,
+        })
+    }
+
+    /// Parses `key: value` pairs up to (and including) the closing delimiter.
+    fn mapping(&mut self, close: char) -> Result<Mapping, ParseError> {
+        let mut map = Mapping::new();
+        loop {
+            self.skip_whitespace();
+            if self.eat(close) {
+                return Ok(map);
+            }
+            let key_offset = self.pos;
+            let key = self.key()?;
+            self.skip_whitespace();
+            if !self.eat(':') {
+                return Err(self.error(&format!("expected `:` after argument name `{}`", key)));
+            }
+            let value = self.value()?;
+            if map.insert(Value::String(key.clone()), value).is_some() {
+                return Err(self.error_at(key_offset, &format!("duplicate argument `{}`", key)));
+            }
+            self.skip_whitespace();
+            if !self.eat(',') && !self.rest().starts_with(close) {
+                return Err(self.error(&format!("expected `,` or `{}`", close)));
+            }
+        }
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Value>, ParseError> {
+        let mut seq = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.eat(']') {
+                return Ok(seq);
+            }
+            seq.push(self.value()?);
+            self.skip_whitespace();
+            if !self.eat(',') && !self.rest().starts_with(']') {
+                return Err(self.error("expected `,` or `]`"));
+            }
+        }
+    }
+
+    fn key(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some(q @ ('"' | '\'')) => self.quoted(q),
+            _ => {
+                let len = self
+                    .rest()
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+                    .unwrap_or(self.rest().len());
+                if len == 0 {
+                    return Err(self.error("expected argument name"));
+                }
+                let key = self.rest()[..len].to_string();
+                self.pos += len;
+                Ok(key)
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(q @ ('"' | '\'')) => Ok(Value::String(self.quoted(q)?)),
+            Some('[') => {
+                self.pos += 1;
+                Ok(Value::Sequence(self.sequence()?))
+            }
+            Some('{') => {
+                self.pos += 1;
+                Ok(Value::Mapping(self.mapping('}')?))
+            }
+            _ => self.bare(),
+        }
+    }
+
+    /// Parses an unquoted scalar, which runs up to the next delimiter.
+    fn bare(&mut self) -> Result<Value, ParseError> {
+        let len = self
+            .rest()
+            .find([',', ')', ']', '}', '\n'])
+            .unwrap_or(self.rest().len());
+        let raw = self.rest()[..len].trim();
+        if raw.is_empty() {
+            return Err(self.error("expected a value"));
+        }
+        self.pos += len;
+        Ok(match raw {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            "null" | "~" => Value::Null,
+            _ => {
+                if let Ok(i) = raw.parse::<i64>() {
+                    Value::Number(Number::from(i))
+                } else if let Some(f) = raw.parse::<f64>().ok().filter(|f| f.is_finite()) {
+                    Value::Number(Number::from(f))
+                } else {
+                    Value::String(raw.to_string())
+                }
+            }
+        })
+    }
+
+    /// Parses a quoted string, handling backslash escapes.
+    fn quoted(&mut self, quote: char) -> Result<String, ParseError> {
+        let open = self.pos;
+        self.pos += quote.len_utf8();
+        let mut s = String::new();
+        loop {
+            let c = match self.next() {
+                None => return Err(self.error_at(open, "unterminated string")),
+                Some(c) => c,
+            };
+            if c == quote {
+                return Ok(s);
+            }
+            if c != '\\' {
+                s.push(c);
+                continue;
+            }
+            let escape = self.pos - 1;
+            match self.next() {
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some('r') => s.push('\r'),
+                Some('0') => s.push('\0'),
+                Some(c @ ('\\' | '"' | '\'' | '/')) => s.push(c),
+                Some('u') => {
+                    let hex = self.rest().get(..4).unwrap_or("");
+                    match u32::from_str_radix(hex, 16).ok().and_then(char::from_u32) {
+                        Some(c) if hex.len() == 4 => {
+                            s.push(c);
+                            self.pos += 4;
+                        }
+                        _ => {
+                            return Err(self.error_at(
+                                escape,
+                                "invalid unicode escape, expected `\\u` followed by 4 hex digits",
+                            ))
+                        }
+                    }
+                }
+                Some(c) => {
+                    return Err(self.error_at(escape, &format!("unknown escape sequence `\\{}`", c)))
+                }
+                None => return Err(self.error_at(open, "unterminated string")),
+            }
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", c)))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let len = self.rest().len() - self.rest().trim_start().len();
+        self.pos += len;
+    }
+
+    fn error(&self, message: &str) -> ParseError {
+        self.error_at(self.pos, message)
+    }
+
+    fn error_at(&self, offset: usize, message: &str) -> ParseError {
+        ParseError {
+            offset,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(text: &str) -> Vec<Found> {
+        find_calls(text, &code_ranges(text))
+    }
+
+    fn call(text: &str) -> Call {
+        match find(text).as_slice() {
+            [Found::Call(c)] => c.clone(),
+            other => panic!("expected one call in {:?}, found {:?}", text, other),
+        }
+    }
+
+    fn malformed(text: &str) -> ParseError {
+        match find(text).as_slice() {
+            [Found::Malformed(e)] => e.clone(),
+            other => panic!("expected a malformed call in {:?}, found {:?}", text, other),
+        }
+    }
+
+    fn arg(c: &Call, key: &str) -> Value {
+        c.arguments.get(key).cloned().unwrap_or(Value::Null)
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(s.into())
+    }
+
+    #[test]
+    fn parses_name_path_and_position() {
+        let text = "a $%%{blog/posts:card(n: 1)} b";
+        let c = call(text);
+        assert_eq!(c.name, "card");
+        assert_eq!(c.metadata_path.as_deref(), Some("blog/posts"));
+        assert_eq!(&text[c.start..c.end], "$%%{blog/posts:card(n: 1)}");
+        assert_eq!(call("$%%{ nav() }").arguments.len(), 0);
+    }
+
+    #[test]
+    fn parses_values() {
+        let c = call(
+            "$%%{x(s: some text, i: 3, f: 0.5, b: false, n: null, l: [a, \"b, c\"], m: {k: [1]})}",
+        );
+        assert_eq!(arg(&c, "s"), string("some text"));
+        assert_eq!(arg(&c, "i"), Value::Number(3.into()));
+        assert_eq!(arg(&c, "f"), Value::Number(0.5.into()));
+        assert_eq!(arg(&c, "b"), Value::Bool(false));
+        assert_eq!(arg(&c, "n"), Value::Null);
+        assert_eq!(
+            arg(&c, "l"),
+            Value::Sequence(vec![string("a"), string("b, c")])
+        );
+        let mut m = Mapping::new();
+        m.insert(string("k"), Value::Sequence(vec![Value::Number(1.into())]));
+        assert_eq!(arg(&c, "m"), Value::Mapping(m));
+    }
+
+    #[test]
+    fn parses_nested_quotes() {
+        let c = call(r#"$%%{x(a: "it's (fine)", b: 'say "hi", then }', c: "\"q\"")}"#);
+        assert_eq!(arg(&c, "a"), string("it's (fine)"));
+        assert_eq!(arg(&c, "b"), string("say \"hi\", then }"));
+        assert_eq!(arg(&c, "c"), string("\"q\""));
+    }
+
+    #[test]
+    fn parses_escapes() {
+        let c = call(r#"$%%{x(a: "1\n2\t3\\4é5\'")}"#);
+        assert_eq!(arg(&c, "a"), string("1\n2\t3\\4\u{e9}5'"));
+        let e = malformed(r#"$%%{x(a: "\q")}"#);
+        assert_eq!(e.message, "unknown escape sequence `\\q`");
+        assert_eq!(e.offset, 10);
+        assert!(malformed(r#"$%%{x(a: "\u12")}"#)
+            .message
+            .starts_with("invalid unicode escape"));
+    }
+
+    #[test]
+    fn escaped_markers() {
+        let text = r"\$%%{x()} \\$%%{y()}";
+        match find(text).as_slice() {
+            [Found::Escape(0), Found::Call(c)] => assert_eq!(c.name, "y"),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn skips_code() {
+        let text =
+            "`$%%{a()}` and ``x ` $%%{b()}``\n\n```md\n$%%{c()}\n```\n~~~\n$%%{d()}\n~~~\n$%%{e()}";
+        let names: Vec<_> = find(text)
+            .into_iter()
+            .map(|f| match f {
+                Found::Call(c) => c.name,
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert_eq!(names, vec!["e"]);
+    }
+
+    #[test]
+    fn code_ranges_end_at_fences_and_paragraphs() {
+        let text = "```\nx\n```\n`a\n\nb `c`";
+        let ranges = code_ranges(text);
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(&text[ranges[0].clone()], "```\nx\n```\n");
+        assert_eq!(&text[ranges[1].clone()], "`c`");
+        // an unclosed fence runs to the end of the text
+        assert_eq!(code_ranges("a\n```\n$%%{x()}"), vec![2..14]);
+    }
+
+    #[test]
+    fn reports_malformed_calls() {
+        let cases = [
+            ("$%%{x}", 5, "expected `(` after snippet name"),
+            ("$%%{(a: 1)}", 4, "snippet is missing name"),
+            ("$%%{:x()}", 4, "metadata path before `:` is empty"),
+            (
+                "$%%{my card()}",
+                6,
+                "snippet name cannot contain whitespace",
+            ),
+            ("$%%{x(a 1)}", 8, "expected `:` after argument name `a`"),
+            ("$%%{x(a: 1, a: 2)}", 12, "duplicate argument `a`"),
+            ("$%%{x(a: \"1)}", 9, "unterminated string"),
+            ("$%%{x(a: [1, 2)}", 14, "expected `,` or `]`"),
+            ("$%%{x(a: )}", 9, "expected a value"),
+            ("$%%{x() b", 8, "expected `}`"),
+        ];
+        for (text, offset, message) in cases {
+            let e = malformed(text);
+            assert_eq!(
+                (e.offset, e.message.as_str()),
+                (offset, message),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn resumes_after_malformed_calls() {
+        let found = find("$%%{x( $%%{y()}");
+        assert!(matches!(found.as_slice(), [Found::Malformed(_), Found::Call(c)] if c.name == "y"));
+    }
+}
@@ -1,33 +1,1229 @@
-#[derive(serde::Deserialize, Clone)]
-#[serde(rename_all = "snake_case")]
+use crate::diagnostic::Diagnostic;
+use crate::errors::*;
+use crate::frontmatter::{offset_of, strip_location};
+use crate::shell;
+use crate::vfs::Folder;
+use error_chain::bail;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The contents of `span.yml`. Every field is optional; see the README for defaults.
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(rename_all = "snake_case", default, deny_unknown_fields)]
 pub struct Config {
     pub ignore: Vec<String>,
     pub passthrough: Vec<String>,
     pub pre_run: Vec<PreRun>,
+    /// Like pre_run, but run on the built site, after pandoc.
+    pub post_run: Vec<PreRun>,
+    /// The steps of the build, in order.
+    pub stages: Vec<StageConfig>,
+    /// Programs that hook into the build; see plugins.rs.
+    pub plugins: Vec<Plugin>,
+    /// Rhai scripts that transform content files or add to their metadata, before pandoc.
+    pub scripts: Vec<Script>,
+    /// Rhai scripts whose functions snippets can use as filters.
+    pub snippet_filters: Vec<PathBuf>,
     pub filters: Vec<Filter>,
     pub extra_args: Vec<String>,
     pub default_template: String,
+    pub snippets_in_metadata: bool,
+    /// Site-wide values, available to snippets and templates as `site.*`.
+    pub site: Mapping,
+    /// Whether to build content files marked `draft: true`.
+    pub drafts: bool,
+    /// How to run pandoc.
+    pub pandoc: RunOptions,
+    /// Overrides for the rest of the config, keyed by profile name.
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Settings that replace the base config's when a profile is selected.
+/// `site` is merged into the base `site` instead of replacing it.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignore: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passthrough: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pre_run: Option<Vec<PreRun>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_run: Option<Vec<PreRun>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stages: Option<Vec<StageConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plugins: Option<Vec<Plugin>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scripts: Option<Vec<Script>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet_filters: Option<Vec<PathBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filters: Option<Vec<Filter>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippets_in_metadata: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site: Option<Mapping>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drafts: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pandoc: Option<RunOptions>,
+}
+
+/// The name of the per-folder config files in contents/.
+pub const DIR_FILE_NAME: &str = "_span.yml";
+
+/// Settings from a `_span.yml` file in contents/, which apply to the files in its folder
+/// and all folders below it. Like a profile, `site` is merged and everything else is
+/// replaced. Globs in `filters` are relative to the folder.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub struct DirConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filters: Option<Vec<Filter>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippets_in_metadata: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub site: Option<Mapping>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drafts: Option<bool>,
+}
+
+/// Reads the `_span.yml` files in a contents folder, keyed by their folder's path
+/// within it.
+pub fn load_directories(contents: &Folder) -> Result<BTreeMap<PathBuf, DirConfig>> {
+    fn load(
+        folder: &Folder,
+        dir: PathBuf,
+        res: &mut BTreeMap<PathBuf, DirConfig>,
+        errors: &mut Vec<String>,
+    ) {
+        if let Some(c) = folder.files.get(OsStr::new(DIR_FILE_NAME)) {
+            let path = folder.path.join(DIR_FILE_NAME);
+            let parsed = String::from_utf8(c.clone())
+                .chain_err(|| format!("{:?} is not valid UTF-8", path))
+                .and_then(|text| parse::<DirConfig>(&path, &text));
+            match parsed {
+                Ok(d) => {
+                    res.insert(dir.clone(), d);
+                }
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        for (name, f) in folder.folders.iter() {
+            load(f, dir.join(name), res, errors);
+        }
+    }
+    let mut res = BTreeMap::new();
+    let mut errors = Vec::new();
+    load(contents, PathBuf::new(), &mut res, &mut errors);
+    if !errors.is_empty() {
+        bail!(errors.join("\n\n"));
+    }
+    Ok(res)
+}
+
+impl DirConfig {
+    /// Applies these settings, from the `_span.yml` in dir, to config.
+    fn apply(&self, config: &mut Config, dir: &Path) {
+        if let Some(ref v) = self.filters {
+            config.filters = v
+                .iter()
+                .map(|f| Filter {
+                    path: f.path.clone(),
+                    files: f
+                        .files
+                        .iter()
+                        .map(|g| match dir.to_str() {
+                            Some("") => g.clone(),
+                            _ => escape_glob(&dir.to_string_lossy()) + "/" + g.as_str(),
+                        })
+                        .collect(),
+                })
+                .collect();
+        }
+        if let Some(ref v) = self.extra_args {
+            config.extra_args = v.clone();
+        }
+        if let Some(ref v) = self.default_template {
+            config.default_template = v.clone();
+        }
+        if let Some(v) = self.snippets_in_metadata {
+            config.snippets_in_metadata = v;
+        }
+        if let Some(v) = self.drafts {
+            config.drafts = v;
+        }
+        if let Some(ref overrides) = self.site {
+            config.merge_site(overrides.clone());
+        }
+    }
+}
+
+/// Escapes the characters that have a meaning in globs.
+fn escape_glob(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '*' | '?' | '[' | ']' | '{' | '}' => format!("[{}]", c),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            ignore: Vec::new(),
+            passthrough: Vec::new(),
+            pre_run: Vec::new(),
+            post_run: Vec::new(),
+            stages: vec![
+                StageConfig::PreRun,
+                StageConfig::Passthrough,
+                StageConfig::Pandoc,
+                StageConfig::PostRun,
+            ],
+            plugins: Vec::new(),
+            scripts: Vec::new(),
+            snippet_filters: Vec::new(),
+            filters: Vec::new(),
+            extra_args: Vec::new(),
+            default_template: "default.html".into(),
+            snippets_in_metadata: false,
+            site: Mapping::new(),
+            drafts: false,
+            pandoc: RunOptions::default(),
+            profiles: BTreeMap::new(),
+        }
+    }
+}
+
+/// Names a config file can have, in order of preference.
+pub const FILE_NAMES: [&str; 3] = ["span.yml", "span.toml", "span.json"];
+
+/// Finds the config file: the given path if there is one, otherwise the first
+/// directory from the input directory upward that has one of FILE_NAMES.
+pub fn find(input: &Path, given: Option<&Path>) -> Result<PathBuf> {
+    if let Some(path) = given {
+        if !path.is_file() {
+            bail!("config file {:?} does not exist", path);
+        }
+        return Ok(path.to_path_buf());
+    }
+    let absolute =
+        fs::canonicalize(input).chain_err(|| format!("could not find directory {:?}", input))?;
+    // the input directory itself is checked by its given path, so messages stay short
+    let dirs = std::iter::once(input.to_path_buf())
+        .chain(absolute.ancestors().skip(1).map(Path::to_path_buf));
+    for dir in dirs {
+        let found = FILE_NAMES
+            .iter()
+            .map(|n| dir.join(n))
+            .filter(|p| p.is_file())
+            .collect::<Vec<_>>();
+        match found.len() {
+            0 => continue,
+            1 => return Ok(found[0].clone()),
+            _ => bail!(
+                "found more than one config file in {:?} ({}); remove all but one, or choose one with --config",
+                dir,
+                found
+                    .iter()
+                    .filter_map(|p| p.file_name())
+                    .map(|n| n.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+    bail!(
+        "could not find {} in {:?} or any folder above it",
+        FILE_NAMES
+            .join(", ")
+            .replacen(", span.json", " or span.json", 1),
+        input
+    )
+}
+
+impl Config {
+    /// Reads and checks a config file. Errors point at the offending line in the file.
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path).chain_err(|| format!("could not open {:?}", path))?;
+        let config: Config = parse(path, &text)?;
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Checks the parts of the config that parsing doesn't, like globs.
+    fn check(&self) -> Result<()> {
+        let mut globs: Vec<(&str, &String)> = Vec::new();
+        globs.extend(self.ignore.iter().map(|g| ("ignore", g)));
+        globs.extend(self.passthrough.iter().map(|g| ("passthrough", g)));
+        for pr in self.pre_run.iter() {
+            globs.extend(pr.files.iter().map(|g| ("pre_run", g)));
+        }
+        for pr in self.post_run.iter() {
+            globs.extend(pr.files.iter().map(|g| ("post_run", g)));
+        }
+        for filter in self.filters.iter() {
+            globs.extend(filter.files.iter().map(|g| ("filters", g)));
+        }
+        for stage in self.stages.iter() {
+            globs.extend(stage.files().iter().map(|g| ("stages", g)));
+        }
+        for plugin in self.plugins.iter() {
+            globs.extend(plugin.files.iter().map(|g| ("plugins", g)));
+        }
+        for script in self.scripts.iter() {
+            globs.extend(script.files.iter().map(|g| ("scripts", g)));
+        }
+        for p in self.profiles.values() {
+            globs.extend(p.ignore.iter().flatten().map(|g| ("ignore", g)));
+            globs.extend(p.passthrough.iter().flatten().map(|g| ("passthrough", g)));
+            for pr in p.pre_run.iter().flatten() {
+                globs.extend(pr.files.iter().map(|g| ("pre_run", g)));
+            }
+            for pr in p.post_run.iter().flatten() {
+                globs.extend(pr.files.iter().map(|g| ("post_run", g)));
+            }
+            for filter in p.filters.iter().flatten() {
+                globs.extend(filter.files.iter().map(|g| ("filters", g)));
+            }
+            for stage in p.stages.iter().flatten() {
+                globs.extend(stage.files().iter().map(|g| ("stages", g)));
+            }
+            for plugin in p.plugins.iter().flatten() {
+                globs.extend(plugin.files.iter().map(|g| ("plugins", g)));
+            }
+            for script in p.scripts.iter().flatten() {
+                globs.extend(script.files.iter().map(|g| ("scripts", g)));
+            }
+        }
+        let mut errors = globs
+            .into_iter()
+            .filter_map(|(key, g)| {
+                globset::Glob::new(g)
+                    .err()
+                    .map(|e| format!("invalid glob {:?} in {}: {}", g, key, e.kind()))
+            })
+            .collect::<Vec<_>>();
+        if self.default_template.is_empty() {
+            errors.push("default_template can't be empty".into());
+        }
+        let profiles = self.profiles.values();
+        let pre_run = self
+            .pre_run
+            .iter()
+            .chain(profiles.clone().flat_map(|p| p.pre_run.iter().flatten()));
+        let post_run = self
+            .post_run
+            .iter()
+            .chain(profiles.flat_map(|p| p.post_run.iter().flatten()));
+        for (key, pr) in pre_run
+            .map(|pr| ("pre_run", pr))
+            .chain(post_run.map(|pr| ("post_run", pr)))
+        {
+            match (&pr.command, pr.builtin) {
+                (Some(_), Some(_)) | (None, None) => errors.push(format!(
+                    "each entry in {} needs either a command or a builtin, but not both",
+                    key
+                )),
+                (None, Some(b)) if key == "pre_run" => errors.push(format!(
+                    "builtin {} can only be used in post_run, on the built site",
+                    b
+                )),
+                _ => {}
+            }
+            if pr.render_outputs && key == "post_run" {
+                errors.push(format!(
+                    "render_outputs of {} only applies to pre_run, since post_run runs after pandoc",
+                    pr
+                ));
+            }
+        }
+        let plugins = self.plugins.iter().chain(
+            self.profiles
+                .values()
+                .flat_map(|p| p.plugins.iter().flatten()),
+        );
+        for plugin in plugins {
+            if plugin.hooks.is_empty() {
+                errors.push(format!("plugin ({}) has no hooks", plugin.command));
+            }
+            let per_file = plugin.hooks.iter().any(|h| *h != Hook::SiteFinalize);
+            if per_file && plugin.files.is_empty() {
+                errors.push(format!(
+                    "plugin ({}) needs files to know which files to run {} on",
+                    plugin.command,
+                    plugin
+                        .hooks
+                        .iter()
+                        .filter(|h| **h != Hook::SiteFinalize)
+                        .map(|h| h.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" and ")
+                ));
+            }
+        }
+        errors.extend(check_stages(
+            &self.stages,
+            &self.pre_run,
+            &self.passthrough,
+            &self.post_run,
+        ));
+        // a profile can break the stages by changing them or the settings they use
+        for (name, p) in self.profiles.iter().filter(|(_, p)| {
+            p.stages.is_some()
+                || p.pre_run.is_some()
+                || p.passthrough.is_some()
+                || p.post_run.is_some()
+        }) {
+            let problems = check_stages(
+                p.stages.as_ref().unwrap_or(&self.stages),
+                p.pre_run.as_ref().unwrap_or(&self.pre_run),
+                p.passthrough.as_ref().unwrap_or(&self.passthrough),
+                p.post_run.as_ref().unwrap_or(&self.post_run),
+            );
+            errors.extend(
+                problems
+                    .into_iter()
+                    .map(|e| format!("in profile {}: {}", name, e)),
+            );
+        }
+        if !errors.is_empty() {
+            bail!(errors.join("\n"));
+        }
+        Ok(())
+    }
+
+    /// Applies the settings of the given profile. If the profile isn't defined, this is
+    /// an error when it was asked for explicitly, and does nothing otherwise.
+    pub fn use_profile(&mut self, profile: &str, explicit: bool) -> Result<()> {
+        let p = match self.profiles.get(profile) {
+            Some(p) => p.clone(),
+            None if !explicit => return Ok(()),
+            None => bail!(
+                "profile {} is not defined in the config (defined profiles: {})",
+                profile,
+                names(&self.profiles)
+            ),
+        };
+        if let Some(v) = p.ignore {
+            self.ignore = v;
+        }
+        if let Some(v) = p.passthrough {
+            self.passthrough = v;
+        }
+        if let Some(v) = p.pre_run {
+            self.pre_run = v;
+        }
+        if let Some(v) = p.post_run {
+            self.post_run = v;
+        }
+        if let Some(v) = p.stages {
+            self.stages = v;
+        }
+        if let Some(v) = p.plugins {
+            self.plugins = v;
+        }
+        if let Some(v) = p.scripts {
+            self.scripts = v;
+        }
+        if let Some(v) = p.snippet_filters {
+            self.snippet_filters = v;
+        }
+        if let Some(v) = p.filters {
+            self.filters = v;
+        }
+        if let Some(v) = p.extra_args {
+            self.extra_args = v;
+        }
+        if let Some(v) = p.default_template {
+            self.default_template = v;
+        }
+        if let Some(v) = p.snippets_in_metadata {
+            self.snippets_in_metadata = v;
+        }
+        if let Some(v) = p.drafts {
+            self.drafts = v;
+        }
+        if let Some(v) = p.pandoc {
+            self.pandoc = v;
+        }
+        if let Some(overrides) = p.site {
+            self.merge_site(overrides);
+        }
+        Ok(())
+    }
+
+    /// Returns the config for a file, given its path within contents/: this config,
+    /// with the `_span.yml` files of the folders it's in applied from the top down.
+    pub fn for_file(&self, directories: &BTreeMap<PathBuf, DirConfig>, file: &Path) -> Config {
+        let mut res = self.clone();
+        for (dir, d) in directories.iter() {
+            // BTreeMap keeps parent folders before their children
+            if file.parent().is_some_and(|p| p.starts_with(dir)) {
+                d.apply(&mut res, dir);
+            }
+        }
+        res
+    }
+
+    /// Merges overrides into `site`.
+    pub fn merge_site(&mut self, overrides: Mapping) {
+        let mut site = Value::Mapping(std::mem::take(&mut self.site));
+        merge(&mut site, Value::Mapping(overrides));
+        if let Value::Mapping(m) = site {
+            self.site = m;
+        }
+    }
+
+    /// Applies only the `site` of the given profile, for selecting an environment
+    /// (such as staging) on top of the profile in use.
+    pub fn use_environment(&mut self, environment: &str) -> Result<()> {
+        match self.profiles.get(environment) {
+            None => bail!(
+                "environment {} is not defined in the config (environments are profiles, and the defined profiles are: {})",
+                environment,
+                names(&self.profiles)
+            ),
+            Some(p) => {
+                if let Some(overrides) = p.site.clone() {
+                    self.merge_site(overrides);
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Lists the keys of a map for error messages.
+fn names<T>(map: &BTreeMap<String, T>) -> String {
+    if map.is_empty() {
+        "none".to_string()
+    } else {
+        map.keys().cloned().collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// Merges overlay into base. Mappings are merged key by key;
+/// any other value in overlay replaces the one in base.
+pub fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(b), Value::Mapping(o)) => {
+            for (k, v) in o {
+                match b.get_mut(&k) {
+                    Some(existing) => merge(existing, v),
+                    None => {
+                        b.insert(k, v);
+                    }
+                }
+            }
+        }
+        (b, o) => *b = o,
+    }
+}
+
+/// Returns the problems with a list of stages, given the settings the stages use.
+fn check_stages(
+    stages: &[StageConfig],
+    pre_run: &[PreRun],
+    passthrough: &[String],
+    post_run: &[PreRun],
+) -> Vec<String> {
+    let mut errors = Vec::new();
+    let position = |s: &StageConfig| stages.iter().position(|x| x.name() == s.name());
+    let pandoc = position(&StageConfig::Pandoc);
+    let counts = |s: &StageConfig| stages.iter().filter(|x| x.name() == s.name()).count();
+    for s in [
+        StageConfig::PreRun,
+        StageConfig::Passthrough,
+        StageConfig::Pandoc,
+        StageConfig::PostRun,
+    ] {
+        if counts(&s) > 1 {
+            errors.push(format!("stage {} can only be used once", s.name()));
+        }
+    }
+    if pandoc.is_none() {
+        errors.push("stages must include pandoc".into());
+    }
+    for (i, s) in stages.iter().enumerate() {
+        let before = match s {
+            StageConfig::PreRun
+            | StageConfig::Passthrough
+            | StageConfig::FrontmatterInject { .. } => true,
+            StageConfig::PostRun => false,
+            _ => continue,
+        };
+        if pandoc.is_some_and(|p| (i < p) != before) {
+            errors.push(format!(
+                "stage {} must come {} pandoc",
+                s.name(),
+                if before { "before" } else { "after" }
+            ));
+        }
+    }
+    // the files pre-run commands write elsewhere are moved by the passthrough stage
+    let passes_outputs = pre_run
+        .iter()
+        .any(|pr| pr.writes_files() && !pr.render_outputs);
+    if let (true, Some(r)) = (passes_outputs, position(&StageConfig::PreRun)) {
+        if position(&StageConfig::Passthrough).is_none_or(|p| p < r) {
+            errors.push(
+                "pre_run has commands that write files, which are passed through (unless \
+                 render_outputs is set), so stages must include passthrough after pre-run"
+                    .into(),
+            );
+        }
+    }
+    for (stage, used) in [
+        (StageConfig::PreRun, !pre_run.is_empty()),
+        (StageConfig::Passthrough, !passthrough.is_empty()),
+        (StageConfig::PostRun, !post_run.is_empty()),
+    ] {
+        if used && position(&stage).is_none() {
+            errors.push(format!(
+                "{} is set, but stages doesn't include {}",
+                stage.name().replace('-', "_"),
+                stage.name()
+            ));
+        }
+    }
+    errors
+}
+
+/// A step of the build. Before pandoc, steps work on the files in contents/ (and
+/// passthrough moves its files out of there); after it, on the built site.
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(tag = "name", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum StageConfig {
+    /// Runs the commands in pre_run.
+    PreRun,
+    /// Puts the files that match passthrough in the built site as they are.
+    Passthrough,
+    /// Renders the files in contents/ with pandoc.
+    Pandoc,
+    /// Runs the commands in post_run.
+    PostRun,
+    /// Removes comments and extra whitespace from HTML and CSS files.
+    Minify { files: Vec<String> },
+    /// Copies files to the path given by `to` (see stages::target).
+    Copy { files: Vec<String>, to: String },
+    /// Moves files to the path given by `to` (see stages::target).
+    Rename { files: Vec<String>, to: String },
+    /// Adds values to the front matter of files, replacing existing ones if `overwrite`.
+    FrontmatterInject {
+        files: Vec<String>,
+        values: Mapping,
+        #[serde(default)]
+        overwrite: bool,
+    },
+}
+
+impl StageConfig {
+    /// The name of the stage, as used in the config.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StageConfig::PreRun => "pre-run",
+            StageConfig::Passthrough => "passthrough",
+            StageConfig::Pandoc => "pandoc",
+            StageConfig::PostRun => "post-run",
+            StageConfig::Minify { .. } => "minify",
+            StageConfig::Copy { .. } => "copy",
+            StageConfig::Rename { .. } => "rename",
+            StageConfig::FrontmatterInject { .. } => "frontmatter-inject",
+        }
+    }
+
+    /// The globs of the files a built-in stage works on.
+    fn files(&self) -> &[String] {
+        match self {
+            StageConfig::Minify { files }
+            | StageConfig::Copy { files, .. }
+            | StageConfig::Rename { files, .. }
+            | StageConfig::FrontmatterInject { files, .. } => files,
+            _ => &[],
+        }
+    }
+}
+
+/// A program that extends the build, run at the points given by hooks. It's sent a
+/// request as JSON on stdin and answers with JSON on stdout (see plugins.rs).
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Plugin {
+    pub command: CommandLine,
+    pub hooks: Vec<Hook>,
+    /// The files that pre-render and post-render run on.
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// Settings for the plugin, sent to it as they are.
+    #[serde(default, skip_serializing_if = "Value::is_null")]
+    pub config: Value,
+    /// See RunOptions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    #[serde(default)]
+    pub retries: u32,
+}
+
+impl Plugin {
+    /// Returns the settings for running the plugin.
+    pub fn run_options(&self) -> RunOptions {
+        RunOptions {
+            timeout: self.timeout,
+            env: self.env.clone(),
+            cwd: self.cwd.clone(),
+            retries: self.retries,
+        }
+    }
+}
+
+/// A Rhai script run on content files before pandoc; see scripting.rs.
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Script {
+    /// Path of the script, relative to the site folder.
+    pub path: PathBuf,
+    pub files: Vec<String>,
+}
+
+/// A point in the build that plugins can hook into.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Hook {
+    /// On each source file, before pandoc renders it.
+    PreRender,
+    /// On each file of the built site, after pandoc.
+    PostRender,
+    /// Once, after every stage has run.
+    SiteFinalize,
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Hook::PreRender => "pre-render",
+            Hook::PostRender => "post-render",
+            Hook::SiteFinalize => "site-finalize",
+        })
+    }
 }
 
-#[derive(serde::Deserialize, Clone)]
+/// A command run on the files that match its globs, either on contents/ before
+/// pandoc (pre_run) or on the built site (post_run).
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct PreRun {
-    pub command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<CommandLine>,
+    /// A processor built into span, used instead of a command (post_run only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub builtin: Option<Builtin>,
     pub files: Vec<String>,
-    #[serde(default = "def_error_on")]
-    pub error_on: String,
+    #[serde(default)]
+    pub error_on: ErrorOn,
+    /// Whether a problem found by error_on fails the build or is only reported.
+    #[serde(default)]
+    pub level: Level,
     #[serde(default = "def_replace")]
     pub replace: bool,
+    /// Where to put the command's output instead of replacing the file: a path relative
+    /// to the file's folder, in which `%s` and `%e` are the file's stem and extension.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    /// Whether to keep the file when its output goes elsewhere (see `output` and `%d`).
+    #[serde(default)]
+    pub keep_source: bool,
+    /// Whether pandoc renders the files the command writes elsewhere (see `output` and
+    /// `%d`), instead of them being passed through (pre_run only).
+    #[serde(default)]
+    pub render_outputs: bool,
+    /// See RunOptions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    #[serde(default)]
+    pub retries: u32,
+    /// Whether to store the command's results and reuse them while nothing that could
+    /// change them (see cache::Cache::key) has changed.
+    #[serde(default)]
+    pub cache: bool,
+    /// Command that prints the version of the tool, for the cache. Default: the
+    /// command's program with `--version`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<CommandLine>,
+}
+
+impl PreRun {
+    /// Returns the settings for running the command.
+    pub fn run_options(&self) -> RunOptions {
+        RunOptions {
+            timeout: self.timeout,
+            env: self.env.clone(),
+            cwd: self.cwd.clone(),
+            retries: self.retries,
+        }
+    }
+
+    /// Whether the command writes files other than the one it runs on: to `output`,
+    /// or to the folder `%d` stands for.
+    pub fn writes_files(&self) -> bool {
+        let uses_dir = |arg: &String| {
+            let mut used = false;
+            shell::substitute(arg, &mut |c| {
+                used |= c == 'd';
+                None
+            });
+            used
+        };
+        self.output.is_some()
+            || (self.command.as_ref())
+                .and_then(|c| c.args().ok())
+                .is_some_and(|args| args.iter().any(uses_dir))
+    }
+}
+
+impl fmt::Display for PreRun {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.command, self.builtin) {
+            (Some(c), _) => write!(f, "command ({})", c),
+            (None, Some(b)) => write!(f, "builtin {}", b),
+            (None, None) => write!(f, "command ()"),
+        }
+    }
+}
+
+/// The processors that post_run can use instead of a command. Like a command, each one
+/// "prints" the new contents of the file to stdout and any problems to stderr.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Builtin {
+    /// Removes comments and collapses whitespace in HTML, except in `<pre>`, `<textarea>`,
+    /// `<script>` and `<style>`.
+    MinifyHtml,
+    /// Reports links and sources in HTML that point to files missing from the site.
+    CheckLinks,
+}
+
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Builtin::MinifyHtml => "minify-html",
+            Builtin::CheckLinks => "check-links",
+        })
+    }
+}
+
+/// Settings for running an external command.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunOptions {
+    /// Seconds to wait for the command before killing it (and anything it started).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// Environment variables to set for the command, on top of span's own.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Folder to run the command in, relative to the site folder (the default).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// How many more times to run the command if it times out, can't be started or is
+    /// killed by a signal (see runner::run).
+    pub retries: u32,
 }
 
-#[derive(serde::Deserialize, Clone)]
+/// A command to run: either a command line, split into arguments like a POSIX shell
+/// would (see shell::split), or a list of arguments, which are used as they are.
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(untagged)]
+pub enum CommandLine {
+    Line(String),
+    Args(Vec<String>),
+}
+
+impl CommandLine {
+    /// Returns the program and its arguments.
+    pub fn args(&self) -> Result<Vec<String>> {
+        match self {
+            CommandLine::Line(l) => shell::split(l),
+            CommandLine::Args(a) => Ok(a.clone()),
+        }
+    }
+}
+
+impl fmt::Display for CommandLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandLine::Line(l) => write!(f, "{}", l),
+            CommandLine::Args(a) => write!(f, "{}", shell::join(a)),
+        }
+    }
+}
+
+/// The checks to run on the result of a pre-run command. No checks means `none`.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Default)]
+#[serde(try_from = "Value", into = "Value")]
+pub struct ErrorOn(pub Vec<Check>);
+
+/// Something that's wrong with the result of a pre-run or post-run command.
+#[derive(Clone)]
+pub enum Check {
+    /// It wrote anything to stdout.
+    Stdout,
+    /// It wrote anything to stderr.
+    Stderr,
+    /// It exited with a non-zero status.
+    Status,
+    /// A line of its stdout or stderr matches the regex.
+    Match(Regex),
+}
+
+impl TryFrom<Value> for ErrorOn {
+    type Error = String;
+    fn try_from(value: Value) -> std::result::Result<ErrorOn, String> {
+        let items = match value {
+            Value::Sequence(s) => s,
+            v => vec![v],
+        };
+        let mut checks = Vec::new();
+        for item in items {
+            match item {
+                Value::String(s) => match s.as_str() {
+                    "none" => {}
+                    "stdout" => checks.push(Check::Stdout),
+                    "stderr" => checks.push(Check::Stderr),
+                    "status" => checks.push(Check::Status),
+                    _ => {
+                        return Err(format!(
+                            "unknown value `{}`, expected one of `none`, `stdout`, `stderr`, `status` or `{{match: <regex>}}`",
+                            s
+                        ))
+                    }
+                },
+                Value::Mapping(m) => match (m.len(), m.get("match")) {
+                    (1, Some(Value::String(re))) => checks.push(Check::Match(
+                        Regex::new(re).map_err(|e| format!("invalid regex in match: {}", e))?,
+                    )),
+                    _ => return Err("expected a mapping with only a `match` regex".into()),
+                },
+                _ => {
+                    return Err(
+                        "expected `none`, `stdout`, `stderr`, `status`, `{match: <regex>}` or a list of them"
+                            .into(),
+                    )
+                }
+            }
+        }
+        Ok(ErrorOn(checks))
+    }
+}
+
+impl From<ErrorOn> for Value {
+    fn from(error_on: ErrorOn) -> Value {
+        let mut items = error_on
+            .0
+            .into_iter()
+            .map(|c| match c {
+                Check::Stdout => Value::String("stdout".into()),
+                Check::Stderr => Value::String("stderr".into()),
+                Check::Status => Value::String("status".into()),
+                Check::Match(re) => {
+                    let mut m = Mapping::new();
+                    m.insert("match".into(), re.as_str().into());
+                    Value::Mapping(m)
+                }
+            })
+            .collect::<Vec<_>>();
+        match items.len() {
+            0 => Value::String("none".into()),
+            1 => items.remove(0),
+            _ => Value::Sequence(items),
+        }
+    }
+}
+
+/// How a problem with a pre-run command's result is reported.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    /// Fail the build.
+    #[default]
+    Error,
+    /// Print a warning and carry on.
+    Warning,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct Filter {
     pub path: std::path::PathBuf,
     pub files: Vec<String>,
 }
 
-fn def_error_on() -> String {
-    "none".into()
+/// Parses the text of a config file, in the format given by its extension
+/// (YAML unless it's `.toml` or `.json`). An empty file gives the default value.
+fn parse<T: serde::de::DeserializeOwned + Default>(path: &Path, text: &str) -> Result<T> {
+    let diagnostic = |message: &str, offset: usize| -> Error {
+        let mut d = Diagnostic::new(
+            format!("invalid config: {}", message),
+            path.to_path_buf(),
+            text,
+            offset,
+            offset + 1,
+        );
+        d.notes.extend(suggest(message));
+        ErrorKind::Diagnostics(vec![d]).into()
+    };
+    if text.trim().is_empty() {
+        return Ok(T::default());
+    }
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(text)
+            .map_err(|e| diagnostic(e.message(), e.span().map_or(0, |s| s.start))),
+        Some("json") => serde_json::from_str(text).map_err(|e| {
+            diagnostic(
+                &strip_location(&e.to_string()),
+                offset_of(text, e.line(), e.column()),
+            )
+        }),
+        _ => {
+            if let Ok(Value::Null) = serde_yaml::from_str(text) {
+                return Ok(T::default());
+            }
+            serde_yaml::from_str(text).map_err(|e| {
+                diagnostic(
+                    &strip_location(&e.to_string()),
+                    e.location().map_or(0, |l| l.index()),
+                )
+            })
+        }
+    }
+}
+
+impl Filter {
+    /// Whether the filter applies to a file, given its path within contents/.
+    pub fn matches(&self, file: &Path) -> Result<bool> {
+        let mut builder = globset::GlobSetBuilder::new();
+        for g in self.files.iter() {
+            builder.add(
+                globset::GlobBuilder::new(g)
+                    .literal_separator(true)
+                    .build()
+                    .chain_err(|| format!("couldn't create glob from {}", g))?,
+            );
+        }
+        let set = builder.build().chain_err(|| "couldn't create glob set")?;
+        Ok(set.is_match(file))
+    }
 }
+
+/// Suggests a known key or value for an "unknown field" error from serde (or an error
+/// worded like one), if one is close enough.
+fn suggest(message: &str) -> Option<String> {
+    lazy_static! {
+        static ref UNKNOWN: Regex =
+            Regex::new(r"unknown (?:field|variant|value) `([^`]*)`, expected (.*)").unwrap();
+        static ref KEY: Regex = Regex::new(r"`([^`]*)`").unwrap();
+    }
+    let c = UNKNOWN.captures(message)?;
+    let field = c.get(1)?.as_str();
+    KEY.captures_iter(c.get(2)?.as_str())
+        .filter_map(|k| k.get(1))
+        .map(|k| (strsim::jaro_winkler(field, k.as_str()), k.as_str()))
+        .filter(|(score, _)| *score > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, k)| format!("did you mean `{}`?", k))
+}
+
 fn def_replace() -> bool {
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_on(yaml: &str) -> std::result::Result<Value, String> {
+        ErrorOn::try_from(serde_yaml::from_str::<Value>(yaml).unwrap()).map(Value::from)
+    }
+
+    #[test]
+    fn parses_error_on() {
+        let yaml = |s: &str| Ok(serde_yaml::from_str::<Value>(s).unwrap());
+        assert_eq!(error_on("none"), yaml("none"));
+        assert_eq!(error_on("[]"), yaml("none"));
+        assert_eq!(error_on("status"), yaml("status"));
+        assert_eq!(error_on("[stderr]"), yaml("stderr"));
+        assert_eq!(error_on("[status, none, stdout]"), yaml("[status, stdout]"));
+        assert_eq!(error_on("{match: '^E\\d+'}"), yaml("{match: '^E\\d+'}"));
+        assert_eq!(
+            error_on("[stderr, {match: warn}]"),
+            yaml("[stderr, {match: warn}]")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_error_on() {
+        let err = |s: &str| error_on(s).unwrap_err();
+        assert!(err("{match: '('}").starts_with("invalid regex in match:"));
+        assert!(err("exit").starts_with("unknown value `exit`, expected one of"));
+        assert!(err("[status, exit]").starts_with("unknown value `exit`"));
+        assert_eq!(
+            err("{match: a, level: warning}"),
+            "expected a mapping with only a `match` regex"
+        );
+        assert!(err("1").starts_with("expected `none`, `stdout`"));
+    }
+
+    #[test]
+    fn suggests_keys() {
+        assert_eq!(
+            suggest(
+                "unknown field `pasthrough`, expected one of `ignore`, `passthrough`, `pre_run`"
+            )
+            .as_deref(),
+            Some("did you mean `passthrough`?")
+        );
+        assert_eq!(
+            suggest("unknown variant `minfy`, expected one of `pre-run`, `minify`, `copy`")
+                .as_deref(),
+            Some("did you mean `minify`?")
+        );
+        assert_eq!(
+            suggest("unknown value `stder`, expected one of `none`, `stdout`, `stderr`").as_deref(),
+            Some("did you mean `stderr`?")
+        );
+        assert_eq!(
+            suggest("unknown field `colour`, expected `ignore` or `stages`"),
+            None
+        );
+        assert_eq!(
+            suggest("invalid type: string \"a\", expected a sequence"),
+            None
+        );
+    }
+
+    #[test]
+    fn suggests_keys_in_config_errors() {
+        let e = parse::<Config>(Path::new("span.yml"), "site: {}\ndefault_templte: a.html\n")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(e.contains("span.yml:2:1"), "{}", e);
+        assert!(
+            e.ends_with("= note: did you mean `default_template`?"),
+            "{}",
+            e
+        );
+    }
+
+    fn directories(files: &[(&str, &str)]) -> BTreeMap<PathBuf, DirConfig> {
+        let mut contents = Folder::new(PathBuf::from("contents"));
+        for (path, text) in files {
+            contents
+                .push(PathBuf::from(path), text.as_bytes().to_vec())
+                .unwrap();
+        }
+        load_directories(&contents).unwrap()
+    }
+
+    #[test]
+    fn cascades_folder_settings() {
+        let dirs = directories(&[
+            (
+                "_span.yml",
+                "default_template: a.html\nsite: {x: 1, y: {a: 1}}",
+            ),
+            (
+                "blog/_span.yml",
+                "default_template: b.html\nsite: {y: {b: 2}}",
+            ),
+            ("blog/2024/_span.yml", "extra_args: [--toc]\nsite: {x: 3}"),
+            ("blog-old/_span.yml", "drafts: true"),
+        ]);
+        let config = Config {
+            site: serde_yaml::from_str("{x: 0, z: 0}").unwrap(),
+            ..Config::default()
+        };
+        let site = |s: &str| serde_yaml::from_str::<Mapping>(s).unwrap();
+
+        let c = config.for_file(&dirs, Path::new("blog/2024/p.md"));
+        assert_eq!(c.default_template, "b.html");
+        assert_eq!(c.extra_args, ["--toc"]);
+        assert_eq!(c.site, site("{x: 3, z: 0, y: {a: 1, b: 2}}"));
+        assert!(!c.drafts);
+
+        let c = config.for_file(&dirs, Path::new("blog/p.md"));
+        assert_eq!(c.default_template, "b.html");
+        assert!(c.extra_args.is_empty());
+        assert_eq!(c.site, site("{x: 1, z: 0, y: {a: 1, b: 2}}"));
+
+        // blog-old/ isn't inside blog/, and a folder's own file isn't below it
+        let c = config.for_file(&dirs, Path::new("blog-old/p.md"));
+        assert_eq!(c.default_template, "a.html");
+        assert!(c.drafts);
+        let c = config.for_file(&dirs, Path::new("index.md"));
+        assert_eq!(c.default_template, "a.html");
+        assert_eq!(c.site, site("{x: 1, z: 0, y: {a: 1}}"));
+        let c = config.for_file(&dirs, Path::new("blog"));
+        assert_eq!(c.default_template, "a.html");
+    }
+
+    #[test]
+    fn makes_filter_globs_relative_to_the_folder() {
+        let filters = "filters: [{path: f.lua, files: ['*.md', 'a/**']}]";
+        let dirs = directories(&[
+            ("_span.yml", filters),
+            ("notes/_span.yml", filters),
+            ("notes/[draft] {1}/_span.yml", filters),
+        ]);
+        let globs = |file: &str| {
+            Config::default().for_file(&dirs, Path::new(file)).filters[0]
+                .files
+                .clone()
+        };
+        assert_eq!(globs("x.md"), ["*.md", "a/**"]);
+        assert_eq!(globs("notes/x.md"), ["notes/*.md", "notes/a/**"]);
+        let file = "notes/[draft] {1}/x.md";
+        assert_eq!(
+            globs(file),
+            [
+                "notes/[[]draft[]] [{]1[}]/*.md",
+                "notes/[[]draft[]] [{]1[}]/a/**"
+            ]
+        );
+        let filter = &Config::default().for_file(&dirs, Path::new(file)).filters[0];
+        assert!(filter.matches(Path::new(file)).unwrap());
+        assert!(!filter.matches(Path::new("notes/d 1/x.md")).unwrap());
+    }
+
+    #[test]
+    fn escapes_globs() {
+        assert_eq!(escape_glob("a/b c.md"), "a/b c.md");
+        assert_eq!(escape_glob("*?[]{}"), "[*][?][[][]][{][}]");
+    }
+}
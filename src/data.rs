@@ -0,0 +1,103 @@
+use crate::errors::*;
+use crate::vfs::Folder;
+use error_chain::bail;
+use serde_yaml::{Mapping, Value};
+use std::ffi::OsStr;
+use std::path::Path;
+
+/// Parses the files in the data folder into a mapping keyed by file stem,
+/// with subfolders as nested mappings. YAML (`.yml`, `.yaml`), JSON (`.json`)
+/// and CSV (`.csv`) files are supported; a CSV file becomes a list of rows,
+/// each a mapping from column header to value. Hidden files and folders (like
+/// `.gitkeep`) are skipped, and so are other files, with a warning.
+pub fn load(folder: &Folder) -> Result<Mapping> {
+    let mut res = Mapping::new();
+    let mut errors = Vec::new();
+    for (name, contents) in folder.files.iter() {
+        if hidden(name) {
+            continue;
+        }
+        let path = folder.path.join(name);
+        let stem = match path.file_stem() {
+            Some(s) => s.to_string_lossy().to_string(),
+            None => continue,
+        };
+        match parse(&path, contents) {
+            Err(e) => errors.push(e.to_string()),
+            Ok(None) => eprintln!(
+                "warning: skipping {:?}, which isn't a .yml, .yaml, .json or .csv file",
+                path
+            ),
+            Ok(Some(value)) => {
+                if res.insert(Value::String(stem.clone()), value).is_some() {
+                    errors.push(format!(
+                        "more than one data file in {:?} is named {}",
+                        folder.path, stem
+                    ));
+                }
+            }
+        }
+    }
+    for (name, f) in folder.folders.iter() {
+        if hidden(name) {
+            continue;
+        }
+        let key = Value::String(name.to_string_lossy().to_string());
+        if res.contains_key(&key) {
+            errors.push(format!(
+                "{:?} has both a data file and a folder named {}",
+                folder.path,
+                name.to_string_lossy()
+            ));
+            continue;
+        }
+        match load(f) {
+            Err(e) => errors.push(e.to_string()),
+            Ok(m) => {
+                res.insert(key, Value::Mapping(m));
+            }
+        }
+    }
+    if !errors.is_empty() {
+        bail!(errors.join("\n"));
+    }
+    Ok(res)
+}
+
+fn hidden(name: &OsStr) -> bool {
+    name.to_string_lossy().starts_with('.')
+}
+
+/// Parses a single data file based on its extension. Returns None for files that
+/// aren't data files.
+fn parse(path: &Path, contents: &[u8]) -> Result<Option<Value>> {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    let value = match extension.as_deref() {
+        Some("yml" | "yaml") => serde_yaml::from_slice(contents)
+            .chain_err(|| format!("{:?} contains invalid YAML", path)),
+        Some("json") => {
+            let value: serde_json::Value = serde_json::from_slice(contents)
+                .chain_err(|| format!("{:?} contains invalid JSON", path))?;
+            serde_yaml::to_value(value).chain_err(|| format!("couldn't convert {:?}", path))
+        }
+        Some("csv") => {
+            let mut reader = csv::Reader::from_reader(contents);
+            let headers = reader
+                .headers()
+                .chain_err(|| format!("couldn't read the header row of {:?}", path))?
+                .clone();
+            let mut rows = Vec::new();
+            for record in reader.records() {
+                let record = record.chain_err(|| format!("{:?} contains invalid CSV", path))?;
+                let mut row = Mapping::new();
+                for (h, v) in headers.iter().zip(record.iter()) {
+                    row.insert(Value::String(h.to_string()), Value::String(v.to_string()));
+                }
+                rows.push(Value::Mapping(row));
+            }
+            Ok(Value::Sequence(rows))
+        }
+        _ => return Ok(None),
+    };
+    value.map(Some)
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// An error tied to a location in a source file, rendered like a compiler diagnostic:
+///
+/// ```text
+/// key `title` does not exist
+///   --> contents/index.md:4:7
+///    |
+///  4 | Hello $%%{card(name: x)}
+///    |       ^^^^^^^^^^^^^^^^^^
+///    = snippet: snippets/card.html
+///    = key: title
+/// ```
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    pub file: PathBuf,
+    /// 1-based line number.
+    pub line: usize,
+    /// 1-based column number, counted in characters.
+    pub column: usize,
+    /// Number of characters to underline, starting at `column`.
+    pub length: usize,
+    /// The text of the line the diagnostic points at.
+    pub source_line: String,
+    pub snippet: Option<PathBuf>,
+    pub key: Option<String>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    /// Creates a diagnostic pointing at the byte range `start..end` of `text`,
+    /// which is the contents of `file`. Offsets inside a character are moved out to
+    /// the start (for `start`) or the end (for `end`) of that character.
+    pub fn new(message: String, file: PathBuf, text: &str, start: usize, end: usize) -> Diagnostic {
+        let mut start = start.min(text.len());
+        while !text.is_char_boundary(start) {
+            start -= 1;
+        }
+        let mut end = end.min(text.len());
+        while !text.is_char_boundary(end) {
+            end += 1;
+        }
+        let line_start = text[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = text[start..].find('\n').map_or(text.len(), |i| start + i);
+        let source_line = text[line_start..line_end].trim_end_matches('\r');
+        // only underline up to the end of the first line
+        let end = end.clamp(start, line_end);
+        Diagnostic {
+            message,
+            file,
+            line: text[..start].matches('\n').count() + 1,
+            column: text[line_start..start].chars().count() + 1,
+            length: text[start..end].chars().count().max(1),
+            source_line: source_line.to_string(),
+            snippet: None,
+            key: None,
+            notes: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        writeln!(f, "{}", self.message)?;
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter,
+            self.file.display(),
+            self.line,
+            self.column
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.source_line)?;
+        // keep tabs so that the underline lines up with the source line
+        let padding: String = self
+            .source_line
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(f, "{} | {}{}", gutter, padding, "^".repeat(self.length))?;
+        if let Some(ref snippet) = self.snippet {
+            write!(f, "\n{} = snippet: {}", gutter, snippet.display())?;
+        }
+        if let Some(ref key) = self.key {
+            write!(f, "\n{} = key: {}", gutter, key)?;
+        }
+        for note in self.notes.iter() {
+            write!(f, "\n{} = note: {}", gutter, note)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(text: &str, start: usize, end: usize) -> Diagnostic {
+        Diagnostic::new("bad".into(), "contents/a.md".into(), text, start, end)
+    }
+
+    #[test]
+    fn points_at_line_and_column() {
+        let d = diagnostic("one\r\ntwo $%%{x()}\nthree", 9, 16);
+        assert_eq!((d.line, d.column, d.length), (2, 5, 7));
+        assert_eq!(d.source_line, "two $%%{x()}");
+        // past the end of the line, only the line is underlined
+        let d = diagnostic("ab\ncd", 1, 5);
+        assert_eq!((d.line, d.column, d.length), (1, 2, 1));
+        let d = diagnostic("ab", 7, 9);
+        assert_eq!((d.line, d.column, d.length), (1, 3, 1));
+    }
+
+    #[test]
+    fn counts_characters_and_keeps_to_char_boundaries() {
+        // `é` is two bytes, and these offsets land inside it
+        let text = "body $%%{card(a é)}";
+        let e = text.find('é').unwrap();
+        let d = diagnostic(text, e + 1, e + 1);
+        assert_eq!((d.column, d.length), (17, 1));
+        let d = diagnostic(text, e - 2, e + 1);
+        assert_eq!((d.column, d.length), (15, 3));
+        let d = diagnostic("ü\nß", 1, 4);
+        assert_eq!((d.line, d.column, d.length), (1, 1, 1));
+    }
+
+    #[test]
+    fn display() {
+        let mut d = diagnostic("tïtle: x\n\tkey: $%%{y()}", 16, 23);
+        d.snippet = Some("snippets/y.html".into());
+        d.key = Some("z".into());
+        d.notes.push("more".into());
+        assert_eq!(
+            d.to_string(),
+            "bad\n \
+             --> contents/a.md:2:7\n  \
+             |\n\
+             2 | \tkey: $%%{y()}\n  \
+             | \t     ^^^^^^^\n  \
+             = snippet: snippets/y.html\n  \
+             = key: z\n  \
+             = note: more"
+        );
+    }
+}
@@ -0,0 +1,251 @@
+use crate::diagnostic::Diagnostic;
+use crate::errors::*;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_yaml::{Mapping, Value};
+use std::path::Path;
+
+/// The syntax a front matter block is written in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Delimited by `---` and `---` (or `...`).
+    Yaml,
+    /// Delimited by `+++` and `+++`.
+    Toml,
+    /// A JSON object starting on the first line of the file. A file whose first line
+    /// starts with `{` but that doesn't start with a JSON object has no front matter.
+    Json,
+}
+
+/// The front matter block at the top of a source file, and the body after it.
+#[derive(Clone, Debug)]
+pub struct FrontMatter {
+    /// None if the file has no front matter.
+    pub format: Option<Format>,
+    pub metadata: Mapping,
+    /// Byte offset in the file at which the body starts.
+    pub body_start: usize,
+    pub body: Vec<u8>,
+}
+
+impl FrontMatter {
+    /// Parses the front matter of a file, if it has any.
+    /// Errors point at the offending line in the file.
+    /// Files that aren't UTF-8 are treated as having no front matter.
+    pub fn parse(filepath: &Path, contents: &[u8]) -> Result<FrontMatter> {
+        let none = FrontMatter {
+            format: None,
+            metadata: Mapping::new(),
+            body_start: 0,
+            body: contents.to_vec(),
+        };
+        let text = match std::str::from_utf8(contents) {
+            Ok(t) => t,
+            Err(_) => return Ok(none),
+        };
+        let first_line = text.lines().next().unwrap_or_default().trim_end();
+        let diagnostic = |message: String, start: usize, end: usize| {
+            Error::from_kind(ErrorKind::Diagnostics(vec![Diagnostic::new(
+                message,
+                filepath.to_path_buf(),
+                text,
+                start,
+                end,
+            )]))
+        };
+
+        let (format, metadata, body_start) = match first_line {
+            "---" | "+++" => {
+                let format = if first_line == "---" {
+                    Format::Yaml
+                } else {
+                    Format::Toml
+                };
+                let start = text.find('\n').map_or(text.len(), |i| i + 1);
+                let (end, body_start) = match closing_delimiter(text, start, format) {
+                    Some(e) => e,
+                    None => {
+                        return Err(diagnostic(
+                            "front matter is not terminated".into(),
+                            0,
+                            first_line.len(),
+                        ))
+                    }
+                };
+                let block = &text[start..end];
+                let value = match format {
+                    Format::Yaml => parse_yaml(block),
+                    _ => parse_toml(block),
+                };
+                match value {
+                    Ok(v) => (format, v, body_start),
+                    Err((message, offset)) => {
+                        return Err(diagnostic(message, start + offset, start + offset + 1))
+                    }
+                }
+            }
+            _ if first_line.starts_with('{') => {
+                // only a whole JSON object, with nothing after it on its last line, is front
+                // matter, so that a body starting with `{` (like `{.lead}`) is left alone
+                let mut stream =
+                    serde_json::Deserializer::from_str(text).into_iter::<serde_json::Value>();
+                let value = match stream.next() {
+                    Some(Ok(v @ serde_json::Value::Object(_))) => v,
+                    _ => return Ok(none),
+                };
+                let end = stream.byte_offset();
+                let line_end = text[end..].find('\n').map_or(text.len(), |i| end + i);
+                if !text[end..line_end].trim().is_empty() {
+                    return Ok(none);
+                }
+                let value = serde_yaml::to_value(value)
+                    .chain_err(|| format!("couldn't convert front matter of {:?}", filepath))?;
+                (Format::Json, value, (line_end + 1).min(text.len()))
+            }
+            _ => return Ok(none),
+        };
+
+        let metadata = match metadata {
+            Value::Null => Mapping::new(),
+            Value::Mapping(m) => m,
+            _ => {
+                return Err(diagnostic(
+                    "front matter must be a mapping of keys to values".into(),
+                    0,
+                    first_line.len(),
+                ))
+            }
+        };
+        Ok(FrontMatter {
+            format: Some(format),
+            metadata,
+            body_start,
+            body: contents[body_start..].to_vec(),
+        })
+    }
+
+    /// Returns the file with its front matter written as a YAML block, which is
+    /// what pandoc understands, followed by the given body.
+    pub fn to_yaml_document(&self, body: &[u8]) -> Result<Vec<u8>> {
+        if self.metadata.is_empty() {
+            return Ok(body.to_vec());
+        }
+        let metadata = serde_yaml::to_string(&self.metadata)
+            .chain_err(|| "couldn't serialize front matter")?;
+        Ok(["---\n".as_bytes(), metadata.as_bytes(), b"---\n", body].concat())
+    }
+}
+
+/// Finds the line closing a front matter block that starts at byte offset start.
+/// Returns the offset of the closing line and the offset just past it.
+fn closing_delimiter(text: &str, start: usize, format: Format) -> Option<(usize, usize)> {
+    let mut offset = start;
+    for line in text[start..].split_inclusive('\n') {
+        let l = line.trim_end();
+        let closes = match format {
+            Format::Yaml => l == "---" || l == "...",
+            _ => l == "+++",
+        };
+        if closes {
+            return Some((offset, offset + line.len()));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+/// Parses a YAML block, returning the error message and byte offset within the block on failure.
+fn parse_yaml(block: &str) -> std::result::Result<Value, (String, usize)> {
+    serde_yaml::from_str(block).map_err(|e| {
+        let offset = e.location().map_or(0, |l| l.index());
+        (
+            format!(
+                "invalid YAML front matter: {}",
+                strip_location(&e.to_string())
+            ),
+            offset,
+        )
+    })
+}
+
+/// Parses a TOML block, returning the error message and byte offset within the block on failure.
+fn parse_toml(block: &str) -> std::result::Result<Value, (String, usize)> {
+    match toml::from_str::<toml::Table>(block) {
+        Ok(t) => Ok(from_toml(toml::Value::Table(t))),
+        Err(e) => Err((
+            format!("invalid TOML front matter: {}", e.message()),
+            e.span().map_or(0, |s| s.start),
+        )),
+    }
+}
+
+/// Converts a TOML value to a YAML value. Dates and times become strings.
+fn from_toml(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::Number(i.into()),
+        toml::Value::Float(f) => Value::Number(f.into()),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(a) => Value::Sequence(a.into_iter().map(from_toml).collect()),
+        toml::Value::Table(t) => Value::Mapping(
+            t.into_iter()
+                .map(|(k, v)| (Value::String(k), from_toml(v)))
+                .collect(),
+        ),
+    }
+}
+
+/// Removes the "at line X column Y" that serde errors include,
+/// since diagnostics show the location themselves.
+pub fn strip_location(message: &str) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r" at line \d+ column \d+").unwrap();
+    }
+    RE.replace_all(message, "").to_string()
+}
+
+/// Converts a 1-based line and column into a byte offset.
+pub fn offset_of(text: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = text
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+    let column_offset: usize = text[line_start..]
+        .chars()
+        .take(column.saturating_sub(1))
+        .map(char::len_utf8)
+        .sum();
+    line_start + column_offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> FrontMatter {
+        FrontMatter::parse(Path::new("contents/page.md"), text.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn reads_json_objects() {
+        let fm = parse("{\"title\": \"Home\",\n \"draft\": false}\nBody\n");
+        assert_eq!(fm.format, Some(Format::Json));
+        assert_eq!(fm.metadata.get("title"), Some(&Value::from("Home")));
+        assert_eq!(fm.body, b"Body\n");
+    }
+
+    #[test]
+    fn leaves_attribute_lines_alone() {
+        for text in [
+            "{.lead} is a span\nBody\n",
+            "{\"a\": 1} and more\nBody\n",
+            "{\n",
+        ] {
+            let fm = parse(text);
+            assert_eq!(fm.format, None, "{:?}", text);
+            assert_eq!(fm.body, text.as_bytes());
+        }
+    }
+}
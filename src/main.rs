@@ -3,11 +3,29 @@ use std::{env, fs, path};
 
 mod args;
 mod build;
+mod builtins;
+mod cache;
+mod call;
 mod config;
+mod data;
+mod diagnostic;
+mod frontmatter;
+mod plugins;
+mod runner;
+mod scripting;
+mod shell;
 mod snippets;
+mod stages;
 mod vfs;
 mod errors {
-    error_chain::error_chain! {}
+    error_chain::error_chain! {
+        errors {
+            Diagnostics(d: Vec<crate::diagnostic::Diagnostic>) {
+                description("errors in source files")
+                display("{}", d.iter().map(|x| x.to_string()).collect::<Vec<_>>().join("\n\n"))
+            }
+        }
+    }
 }
 
 use errors::*;
@@ -29,16 +47,13 @@ fn main() {
 
 fn run() -> Result<()> {
     let args = args::Args::parse();
-    match args.command {
+    match args.command.clone() {
         args::Command::Build { input, output } => {
+            let (_, config) = load_config(&input, &args, Some("production"))?;
             let cwd = env::current_dir().chain_err(|| "could not access current directory")?;
             env::set_current_dir(input.clone())
                 .chain_err(|| format!("could not set directory to {:?}", input))?;
             let source = vfs::Folder::read(path::PathBuf::from("."))?;
-            let config_file =
-                fs::File::open("./span.yml").chain_err(|| "could not open ./span.yml")?;
-            let config = serde_yaml::from_reader(config_file)
-                .chain_err(|| "./span.yml contains invalid config syntax")?;
             let result = build::build(source, config)?;
             env::set_current_dir(cwd)
                 .chain_err(|| format!("could not set directory to {:?}", input))?;
@@ -49,12 +64,107 @@ fn run() -> Result<()> {
             }
             fs::create_dir(output.clone())
                 .chain_err(|| format!("could not create output directory {:?}", output.clone()))?;
-            env::set_current_dir(output.clone())
-                .chain_err(|| format!("could not set directory to {:?}", output))?;
             result.write(output)
         }
-        args::Command::Serve { input: _, port: _ } => {
+        args::Command::Config {
+            input,
+            check: _,
+            print,
+            explain,
+        } => {
+            let (path, config) = load_config(&input, &args, None)?;
+            if let Some(file) = explain {
+                explain_config(&input, &path, &config, &file)?;
+            } else if print {
+                print!(
+                    "{}",
+                    serde_yaml::to_string(&config).chain_err(|| "couldn't serialize config")?
+                );
+            } else {
+                println!("{} is valid", path.display());
+            }
+            Ok(())
+        }
+        args::Command::Cache {
+            command: args::CacheCommand::Clean,
+        } => {
+            match cache::clean()? {
+                Some(dir) => println!("deleted {}", dir.display()),
+                None => println!("the cache is already empty"),
+            }
+            Ok(())
+        }
+        args::Command::Serve { input, port: _ } => {
+            // checked now so that config errors show up before serve is implemented
+            load_config(&input, &args, Some("development"))?;
             error_chain::bail!("serve is not implemented yet")
         }
     }
 }
+
+/// Finds and loads the config file for the input folder, and applies the selected
+/// profile (or the command's default profile) and environment, if any.
+/// Returns the path of the config file too.
+fn load_config(
+    input: &path::Path,
+    args: &args::Args,
+    default_profile: Option<&str>,
+) -> Result<(path::PathBuf, config::Config)> {
+    let path = config::find(input, args.config.as_deref())?;
+    let mut config = config::Config::load(&path)?;
+    match args.profile.as_deref().filter(|p| !p.is_empty()) {
+        Some(profile) => config.use_profile(profile, true)?,
+        None => {
+            if let Some(profile) = default_profile {
+                config.use_profile(profile, false)?;
+            }
+        }
+    }
+    if let Some(env) = args.env.as_deref().filter(|e| !e.is_empty()) {
+        config.use_environment(env)?;
+    }
+    Ok((path, config))
+}
+
+/// Prints the settings used for a content file, and the config files they come from.
+fn explain_config(
+    input: &path::Path,
+    config_path: &path::Path,
+    config: &config::Config,
+    file: &path::Path,
+) -> Result<()> {
+    let contents_dir = input.join("contents");
+    let absolute =
+        |p: &path::Path| fs::canonicalize(p).chain_err(|| format!("could not find {:?}", p));
+    let within = absolute(file)?
+        .strip_prefix(absolute(&contents_dir)?)
+        .chain_err(|| format!("{:?} is not in {:?}", file, contents_dir))?
+        .to_path_buf();
+    let contents = vfs::Folder::read(contents_dir.clone())?;
+    let directories = config::load_directories(&contents)?;
+    let mut sources = vec![config_path.display().to_string()];
+    sources.extend(
+        directories
+            .keys()
+            .filter(|d| within.parent().is_some_and(|p| p.starts_with(d)))
+            .map(|d| {
+                contents_dir
+                    .join(d)
+                    .join(config::DIR_FILE_NAME)
+                    .display()
+                    .to_string()
+            }),
+    );
+    let mut settings = serde_yaml::to_value(config.for_file(&directories, &within))
+        .chain_err(|| "couldn't serialize config")?;
+    if let serde_yaml::Value::Mapping(ref mut m) = settings {
+        m.remove("profiles");
+    }
+    println!("# settings for {}", file.display());
+    println!("# from {}", sources.join(", then "));
+    print!(
+        "{}",
+        serde_yaml::to_string(&settings).chain_err(|| "couldn't serialize config")?
+    );
+    Ok(())
+}
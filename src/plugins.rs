@@ -0,0 +1,306 @@
+use crate::config::{self, Config, Hook, Level, Plugin};
+use crate::diagnostic::Diagnostic;
+use crate::errors::*;
+use crate::frontmatter::{offset_of, FrontMatter};
+use crate::runner;
+use crate::vfs::Folder;
+use error_chain::bail;
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+
+/// The version of the protocol, sent with every request. It changes when requests or
+/// responses change in a way that would break existing plugins.
+const PROTOCOL: u32 = 1;
+
+/// What a plugin is sent on stdin, as JSON.
+#[derive(serde::Serialize)]
+struct Request<'a> {
+    protocol: u32,
+    hook: Hook,
+    /// Path of the file within contents/ (pre-render) or the built site (post-render).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<&'a Path>,
+    /// The file's front matter (pre-render).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a Mapping>,
+    /// The file, without its front matter for pre-render.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<&'a str>,
+    /// Paths of all the files of the built site (site-finalize).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<Vec<PathBuf>>,
+    /// The plugin's `config` from span.yml.
+    config: &'a Value,
+    /// The site values that apply to the file, or the site-wide ones for site-finalize.
+    site: &'a Mapping,
+}
+
+/// What a plugin answers on stdout, as JSON. Printing nothing is the same as `{}`.
+#[derive(serde::Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Response {
+    /// Replaces the file's content (its body, for pre-render).
+    content: Option<String>,
+    /// Replaces the file's front matter (pre-render only).
+    metadata: Option<Mapping>,
+    /// Files to add, relative to the file's folder, or to the site for site-finalize.
+    files: Vec<File>,
+    diagnostics: Vec<Message>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    path: PathBuf,
+    content: String,
+}
+
+/// A problem a plugin found. With a line, it points at the file (or, for
+/// site-finalize, at the file given by path); line and column are 1-based and
+/// count from the start of the content the plugin was sent.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Message {
+    message: String,
+    #[serde(default)]
+    level: Level,
+    line: Option<usize>,
+    column: Option<usize>,
+    path: Option<PathBuf>,
+}
+
+/// Runs the plugins that hook into the given point of the build on a folder, whose
+/// paths start with `contents/`: the source files for pre-render, and the built site
+/// for post-render and site-finalize.
+pub fn run(
+    hook: Hook,
+    config: &Config,
+    directories: &BTreeMap<PathBuf, config::DirConfig>,
+    folder: Folder,
+) -> Result<Folder> {
+    let mut folder = folder;
+    for plugin in config.plugins.iter().filter(|p| p.hooks.contains(&hook)) {
+        folder = match hook {
+            Hook::SiteFinalize => finalize(plugin, config, folder)?,
+            _ => per_file(plugin, hook, config, directories, folder)?,
+        };
+    }
+    Ok(folder)
+}
+
+/// Runs a plugin's pre-render or post-render hook on each file it matches.
+fn per_file(
+    plugin: &Plugin,
+    hook: Hook,
+    config: &Config,
+    directories: &BTreeMap<PathBuf, config::DirConfig>,
+    folder: Folder,
+) -> Result<Folder> {
+    // files the plugin adds, added once it has processed every file
+    let mut added = Vec::new();
+    let mut res = folder.map_globs(
+        &plugin.files,
+        &mut |fp, c| {
+            let err_context = format!(", while running its {} hook on {:?}", hook, fp);
+            let within: PathBuf = fp.iter().skip(1).collect();
+            let text = std::str::from_utf8(&c).map_err(|_| {
+                format!(
+                    "plugin ({}) can only be run on text files{}",
+                    plugin.command, err_context
+                )
+            })?;
+            let front_matter = match hook {
+                Hook::PreRender => Some(FrontMatter::parse(&fp, &c)?),
+                _ => None,
+            };
+            let body_start = front_matter.as_ref().map_or(0, |f| f.body_start);
+            let request = Request {
+                protocol: PROTOCOL,
+                hook,
+                path: Some(&within),
+                metadata: front_matter.as_ref().map(|f| &f.metadata),
+                content: Some(&text[body_start..]),
+                files: None,
+                config: &plugin.config,
+                site: &config.for_file(directories, &within).site,
+            };
+            let response = call(plugin, &request, &err_context)?;
+            report(
+                plugin,
+                &response.diagnostics,
+                &err_context,
+                &mut |m| match m.path {
+                    Some(_) => None,
+                    None => Some(Location {
+                        file: fp.clone(),
+                        text: text.to_string(),
+                        start: body_start,
+                    }),
+                },
+            )?;
+            let dir = within.parent().unwrap_or(Path::new("")).to_path_buf();
+            for f in response.files {
+                added.push((
+                    dir.join(relative(plugin, &f.path, &err_context)?),
+                    f.content,
+                ));
+            }
+            let contents = match (front_matter, response.content, response.metadata) {
+                (_, None, None) => c,
+                (Some(mut f), content, metadata) => {
+                    if let Some(m) = metadata {
+                        f.metadata = m;
+                    }
+                    let body = content.map_or(f.body.clone(), String::into_bytes);
+                    f.to_yaml_document(&body)?
+                }
+                (None, _, Some(_)) => bail!(
+                    "plugin ({}) can only change metadata in its pre-render hook{}",
+                    plugin.command,
+                    err_context
+                ),
+                (None, Some(content), None) => content.into_bytes(),
+            };
+            Ok(Some((fp, contents)))
+        },
+        &mut |fp, c| Ok(Some((fp, c))),
+    )?;
+    for (p, c) in added {
+        res.push(p, c.into_bytes())?;
+    }
+    Ok(res)
+}
+
+/// Runs a plugin's site-finalize hook on the built site.
+fn finalize(plugin: &Plugin, config: &Config, folder: Folder) -> Result<Folder> {
+    let err_context = format!(", while running its {} hook", Hook::SiteFinalize);
+    let files = folder
+        .get_globs(&vec!["**".into()])?
+        .into_keys()
+        .map(|p| p.iter().skip(1).collect())
+        .collect();
+    let request = Request {
+        protocol: PROTOCOL,
+        hook: Hook::SiteFinalize,
+        path: None,
+        metadata: None,
+        content: None,
+        files: Some(files),
+        config: &plugin.config,
+        site: &config.site,
+    };
+    let response = call(plugin, &request, &err_context)?;
+    if response.content.is_some() || response.metadata.is_some() {
+        bail!(
+            "plugin ({}) can't return content or metadata from its {} hook, only files{}",
+            plugin.command,
+            Hook::SiteFinalize,
+            err_context
+        );
+    }
+    report(plugin, &response.diagnostics, &err_context, &mut |m| {
+        let path = m.path.as_ref()?;
+        let text = String::from_utf8(folder.get_file(path)?.clone()).ok()?;
+        Some(Location {
+            file: folder.path.join(path),
+            text,
+            start: 0,
+        })
+    })?;
+    let mut folder = folder;
+    for f in response.files {
+        folder.push(
+            relative(plugin, &f.path, &err_context)?,
+            f.content.into_bytes(),
+        )?;
+    }
+    Ok(folder)
+}
+
+/// Sends a request to a plugin and returns its response. Whatever the plugin prints
+/// to stderr is passed on.
+fn call(plugin: &Plugin, request: &Request, err_context: &str) -> Result<Response> {
+    let argv = plugin
+        .command
+        .args()
+        .map_err(|e| format!("invalid plugin command ({}): {}", plugin.command, e))?
+        .into_iter()
+        .map(OsString::from)
+        .collect();
+    let input = serde_json::to_vec(request)
+        .map_err(|e| format!("couldn't serialize plugin request{}: {}", err_context, e))?;
+    let output = runner::run(argv, Some(input), &plugin.run_options(), err_context)?;
+    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    if !output.status.success() {
+        bail!(
+            "plugin ({}) failed ({}){}",
+            plugin.command,
+            output.status,
+            err_context
+        );
+    }
+    if output.stdout.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Response::default());
+    }
+    serde_json::from_slice(&output.stdout).map_err(|e| {
+        format!(
+            "plugin ({}) sent an invalid response{}: {}",
+            plugin.command, err_context, e
+        )
+        .into()
+    })
+}
+
+/// The file a message with a line points at.
+struct Location {
+    file: PathBuf,
+    text: String,
+    /// Byte offset in text at which the content the plugin was sent starts.
+    start: usize,
+}
+
+/// Prints a plugin's warnings and fails with its errors, if there are any.
+fn report(
+    plugin: &Plugin,
+    messages: &[Message],
+    err_context: &str,
+    locate: &mut dyn FnMut(&Message) -> Option<Location>,
+) -> Result<()> {
+    let mut errors = Vec::new();
+    for m in messages {
+        let location = m.line.and_then(|line| Some((line, locate(m)?)));
+        let text = match location {
+            Some((line, l)) => {
+                let offset = l.start + offset_of(&l.text[l.start..], line, m.column.unwrap_or(1));
+                let mut d = Diagnostic::new(m.message.clone(), l.file, &l.text, offset, offset + 1);
+                d.notes
+                    .push(format!("reported by plugin ({})", plugin.command));
+                d.to_string()
+            }
+            None => format!("plugin ({}){}: {}", plugin.command, err_context, m.message),
+        };
+        match m.level {
+            Level::Error => errors.push(text),
+            Level::Warning => eprintln!("warning: {}", text),
+        }
+    }
+    if !errors.is_empty() {
+        bail!(errors.join("\n\n"));
+    }
+    Ok(())
+}
+
+/// Checks that a path a plugin gives for a file it adds stays within its folder.
+fn relative(plugin: &Plugin, path: &Path, err_context: &str) -> Result<PathBuf> {
+    if path.file_name().is_none() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        bail!(
+            "plugin ({}) added file {:?}{}, but files must have relative paths that stay within the folder",
+            plugin.command,
+            path,
+            err_context
+        );
+    }
+    Ok(path.to_path_buf())
+}
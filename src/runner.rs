@@ -0,0 +1,202 @@
+use crate::config::RunOptions;
+use crate::errors::*;
+use std::ffi::OsString;
+use std::process::{Output, Stdio};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::{Child, Command};
+use tokio::runtime::Runtime;
+
+/// Runs a command, given as the program and its arguments, and waits for it.
+/// If stdin is given, it's written to the command's stdin; otherwise the command
+/// gets no stdin at all. A command that times out, can't be started or is killed by a
+/// signal is run again as many times as options allow, and the last result is
+/// returned. One that exits with an error status isn't, since running it again would
+/// most likely fail the same way.
+///
+/// The rest of span is synchronous, so this blocks on a runtime shared by every
+/// command (see runtime), and must not be called from inside another runtime.
+pub fn run(
+    argv: Vec<OsString>,
+    stdin: Option<Vec<u8>>,
+    options: &RunOptions,
+    err_context: &str,
+) -> Result<Output> {
+    let fut = async {
+        let mut attempt = 0;
+        loop {
+            let (result, retry) =
+                match run_once(&argv, stdin.as_deref(), options, err_context).await {
+                    // no exit code means the command was killed by a signal
+                    Ok(output) => {
+                        let signalled = output.status.code().is_none();
+                        (Ok(output), signalled)
+                    }
+                    Err(f) => (Err(f.error), f.retry),
+                };
+            if !retry || attempt >= options.retries {
+                return result;
+            }
+            attempt += 1;
+        }
+    };
+    runtime()?.block_on(fut)
+}
+
+/// Returns the runtime commands run on, starting it the first time.
+fn runtime() -> Result<&'static Runtime> {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    if let Some(r) = RUNTIME.get() {
+        return Ok(r);
+    }
+    let r = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .chain_err(|| "couldn't start the runtime for running commands")?;
+    Ok(RUNTIME.get_or_init(|| r))
+}
+
+/// Why a run of a command has no result.
+struct Failure {
+    error: Error,
+    /// Whether running the command again could work: it couldn't be started or timed out.
+    retry: bool,
+}
+
+impl From<Error> for Failure {
+    fn from(error: Error) -> Failure {
+        Failure {
+            error,
+            retry: false,
+        }
+    }
+}
+
+async fn run_once(
+    argv: &[OsString],
+    stdin: Option<&[u8]>,
+    options: &RunOptions,
+    err_context: &str,
+) -> std::result::Result<Output, Failure> {
+    let program = argv
+        .first()
+        .ok_or_else(|| Error::from(format!("empty command{}", err_context)))?
+        .to_string_lossy()
+        .to_string();
+    let root = std::env::current_dir().chain_err(|| "could not access current directory")?;
+    let mut c = std::process::Command::new(&argv[0]);
+    c.args(&argv[1..])
+        .envs(&options.env)
+        .current_dir(root.join(options.cwd.clone().unwrap_or_default()));
+    // a process group of its own, so that everything it started can be killed on timeout
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut c, 0);
+    let mut c = Command::from(c);
+    let mut child = c
+        .stdin(match stdin {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .chain_err(|| format!("failed to start {}{}", program, err_context))
+        .map_err(|error| Failure { error, retry: true })?;
+
+    let result = match options.timeout {
+        None => communicate(&mut child, stdin, &program).await,
+        Some(t) => {
+            match tokio::time::timeout(
+                Duration::from_secs(t),
+                communicate(&mut child, stdin, &program),
+            )
+            .await
+            {
+                Ok(r) => r,
+                Err(_) => {
+                    kill(&mut child).await;
+                    return Err(Failure {
+                        error: format!("{} timed out after {}s{}", program, t, err_context).into(),
+                        retry: true,
+                    });
+                }
+            }
+        }
+    };
+    result.map_err(|errors| Error::from(format!("{}{}", errors.join("; "), err_context)).into())
+}
+
+/// Writes stdin to a command while reading its stdout and stderr, then waits for it
+/// to exit. Returns every error that happened along the way, except for stdin being
+/// closed early by a command that then exits (see the exit status instead).
+async fn communicate(
+    child: &mut Child,
+    stdin: Option<&[u8]>,
+    program: &str,
+) -> std::result::Result<Output, Vec<String>> {
+    let stdin_pipe = child.stdin.take();
+    let write = async {
+        match (stdin_pipe, stdin) {
+            (Some(mut pipe), Some(data)) => {
+                pipe.write_all(data).await?;
+                // closing stdin tells the command that there's no more input
+                pipe.shutdown().await
+            }
+            _ => Ok(()),
+        }
+    };
+    let (written, stdout, stderr) = tokio::join!(
+        write,
+        read_all(child.stdout.take()),
+        read_all(child.stderr.take()),
+    );
+    let status = child.wait().await;
+
+    let mut errors = Vec::new();
+    // a command that exits without reading all of its input, because it doesn't use
+    // it or because it failed, closes stdin early. its exit status says which
+    let unread = matches!(written, Err(ref e) if e.kind() == std::io::ErrorKind::BrokenPipe)
+        && status.is_ok();
+    if let (Err(e), false) = (written, unread) {
+        errors.push(format!("failed to write to {} stdin: {}", program, e));
+    }
+    if let Err(ref e) = stdout {
+        errors.push(format!("failed to read {} stdout: {}", program, e));
+    }
+    if let Err(ref e) = stderr {
+        errors.push(format!("failed to read {} stderr: {}", program, e));
+    }
+    if let Err(ref e) = status {
+        errors.push(format!("failed to wait on {}: {}", program, e));
+    }
+    match (status, stdout, stderr) {
+        (Ok(status), Ok(stdout), Ok(stderr)) if errors.is_empty() => Ok(Output {
+            status,
+            stdout,
+            stderr,
+        }),
+        _ => Err(errors),
+    }
+}
+
+async fn read_all(pipe: Option<impl AsyncRead + Unpin>) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if let Some(mut p) = pipe {
+        p.read_to_end(&mut buf).await?;
+    }
+    Ok(buf)
+}
+
+/// Kills a command and any processes it started.
+async fn kill(child: &mut Child) {
+    #[cfg(unix)]
+    if let Some(id) = child.id() {
+        // SAFETY: kill has no memory safety requirements; the child leads its process group
+        unsafe {
+            libc::kill(-(id as libc::pid_t), libc::SIGKILL);
+        }
+    }
+    let _ = child.kill().await;
+}
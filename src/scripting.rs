@@ -0,0 +1,254 @@
+use crate::config::Config;
+use crate::diagnostic::Diagnostic;
+use crate::errors::*;
+use crate::frontmatter::{offset_of, FrontMatter};
+use crate::vfs::Folder;
+use error_chain::bail;
+use rhai::{Dynamic, Engine, EvalAltResult, Position, Scope, AST};
+use serde_yaml::{Mapping, Value};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// How many operations a script can run for each call, so that a script stuck in a
+/// loop fails the build instead of hanging it.
+const MAX_OPERATIONS: u64 = 50_000_000;
+
+/// The Rhai scripts the config points at, compiled once per build.
+pub struct Scripts {
+    engine: Engine,
+    /// Scripts that transform content files, with the globs of the files they run on.
+    content: Vec<(Script, Vec<String>)>,
+    /// Scripts whose functions snippets can use as filters.
+    filters: Vec<Script>,
+}
+
+struct Script {
+    path: PathBuf,
+    text: String,
+    ast: AST,
+}
+
+impl Script {
+    fn has_fn(&self, name: &str) -> bool {
+        self.ast.iter_functions().any(|f| f.name == name)
+    }
+
+    /// Turns an error from running the script into a message, pointing at the line of
+    /// the script it happened on when there is one.
+    fn error(&self, function: &str, e: EvalAltResult, err_context: &str) -> String {
+        let mut inner = e;
+        // errors in functions the function called are wrapped, once for each call
+        while let EvalAltResult::ErrorInFunctionCall(_, _, e, _) = inner {
+            inner = *e;
+        }
+        let position = inner.take_position();
+        let message = format!(
+            "error in {}() of script {:?}{}: {}",
+            function, self.path, err_context, inner
+        );
+        match locate(&self.text, position) {
+            Some(offset) => {
+                Diagnostic::new(message, self.path.clone(), &self.text, offset, offset + 1)
+                    .to_string()
+            }
+            None => message,
+        }
+    }
+}
+
+/// Returns the byte offset of a position in a script, if it has one.
+fn locate(text: &str, position: Position) -> Option<usize> {
+    let line = position.line()?;
+    Some(offset_of(text, line, position.position().unwrap_or(1)))
+}
+
+impl Scripts {
+    /// Compiles the scripts in the config, given the site folder, which is the only
+    /// folder scripts can read from.
+    pub fn load(config: &Config, root: &Path) -> Result<Scripts> {
+        let engine = engine(root);
+        let compile = |path: &PathBuf| -> Result<Script> {
+            let text = fs::read_to_string(root.join(path))
+                .chain_err(|| format!("could not read script {:?}", path))?;
+            let ast = engine.compile(&text).map_err(|e| {
+                let offset = locate(&text, e.position()).unwrap_or(0);
+                Error::from_kind(ErrorKind::Diagnostics(vec![Diagnostic::new(
+                    format!("invalid script: {}", e.err_type()),
+                    path.clone(),
+                    &text,
+                    offset,
+                    offset + 1,
+                )]))
+            })?;
+            Ok(Script {
+                path: path.clone(),
+                text,
+                ast,
+            })
+        };
+        let mut content = Vec::new();
+        for s in config.scripts.iter() {
+            let script = compile(&s.path)?;
+            if !script.has_fn("transform") && !script.has_fn("metadata") {
+                bail!(
+                    "script {:?} must define transform(content, path, metadata) or metadata(metadata, path, content)",
+                    s.path
+                );
+            }
+            content.push((script, s.files.clone()));
+        }
+        let filters = config
+            .snippet_filters
+            .iter()
+            .map(compile)
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Scripts {
+            engine,
+            content,
+            filters,
+        })
+    }
+
+    /// Runs the content scripts on the files in contents/ they match: `transform` replaces
+    /// a file's body, and the values `metadata` returns are added to its front matter.
+    pub fn transform(&self, contents: Folder) -> Result<Folder> {
+        let mut contents = contents;
+        for (script, files) in self.content.iter() {
+            contents = contents.map_globs(
+                files,
+                &mut |fp, c| {
+                    let mut front_matter = FrontMatter::parse(&fp, &c)?;
+                    let within: PathBuf = fp.iter().skip(1).collect();
+                    let path = within.to_string_lossy().replace('\\', "/");
+                    let mut body = String::from_utf8(front_matter.body.clone()).map_err(|_| {
+                        format!(
+                            "script {:?} can only be run on text files, but {:?} isn't one",
+                            script.path, fp
+                        )
+                    })?;
+                    if script.has_fn("transform") {
+                        let metadata = to_dynamic(&front_matter.metadata)?;
+                        let res: Dynamic = self
+                            .call(
+                                script,
+                                "transform",
+                                (body.clone(), path.clone(), metadata),
+                                &format!(", while transforming {:?}", fp),
+                            )?;
+                        body = res.into_string().map_err(|t| {
+                            format!(
+                                "transform() of script {:?} must return a string, not {} (for {:?})",
+                                script.path, t, fp
+                            )
+                        })?;
+                    }
+                    if script.has_fn("metadata") {
+                        let metadata = to_dynamic(&front_matter.metadata)?;
+                        let res: Dynamic = self
+                            .call(
+                                script,
+                                "metadata",
+                                (metadata, path, body.clone()),
+                                &format!(", while computing metadata for {:?}", fp),
+                            )?;
+                        let values: Mapping = rhai::serde::from_dynamic(&res).map_err(|e| {
+                            format!(
+                                "metadata() of script {:?} must return a map, for {:?}: {}",
+                                script.path, fp, e
+                            )
+                        })?;
+                        front_matter.metadata.extend(values);
+                    }
+                    Ok(Some((fp, front_matter.to_yaml_document(body.as_bytes())?)))
+                },
+                &mut |fp, c| Ok(Some((fp, c))),
+            )?;
+        }
+        Ok(contents)
+    }
+
+    /// Runs a snippet filter on a value. Errors are messages, for snippets to report.
+    /// A filter must return a string or a number.
+    pub fn filter(&self, name: &str, value: &str) -> std::result::Result<String, String> {
+        let script = self
+            .filters
+            .iter()
+            .find(|s| s.has_fn(name))
+            .ok_or_else(|| format!("filter `{}` is not defined in snippet_filters", name))?;
+        let res: Dynamic = self.call(script, name, (value.to_string(),), "")?;
+        if !(res.is_string() || res.is_int() || res.is_float()) {
+            return Err(format!(
+                "filter `{}` of script {:?} must return a string or a number, not {}",
+                name,
+                script.path,
+                res.type_name()
+            ));
+        }
+        Ok(res.to_string())
+    }
+
+    fn call(
+        &self,
+        script: &Script,
+        function: &str,
+        args: impl rhai::FuncArgs,
+        err_context: &str,
+    ) -> std::result::Result<Dynamic, String> {
+        self.engine
+            .call_fn(&mut Scope::new(), &script.ast, function, args)
+            .map_err(|e| script.error(function, *e, err_context))
+    }
+}
+
+fn to_dynamic(metadata: &Mapping) -> Result<Dynamic> {
+    rhai::serde::to_dynamic(Value::Mapping(metadata.clone()))
+        .map_err(|e| format!("couldn't pass front matter to a script: {}", e).into())
+}
+
+/// Creates the engine scripts run in. It can't load modules or touch the file system,
+/// except for reading files in the site folder with read_file() and file_exists().
+fn engine(root: &Path) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.on_print(|s| eprintln!("{}", s));
+    engine.on_debug(|s, source, pos| eprintln!("{}{}: {}", source.unwrap_or("script"), pos, s));
+    let r = root.to_path_buf();
+    engine.register_fn(
+        "read_file",
+        move |path: &str| -> std::result::Result<String, Box<EvalAltResult>> {
+            let p = sandboxed(&r, path)?;
+            fs::read_to_string(&p).map_err(|e| format!("could not read {:?}: {}", path, e).into())
+        },
+    );
+    let r = root.to_path_buf();
+    engine.register_fn(
+        "file_exists",
+        move |path: &str| -> std::result::Result<bool, Box<EvalAltResult>> {
+            Ok(sandboxed(&r, path)?.is_file())
+        },
+    );
+    engine
+}
+
+/// Resolves a path given to a script function, which must be relative to the site
+/// folder and stay within it (symbolic links included).
+fn sandboxed(root: &Path, path: &str) -> std::result::Result<PathBuf, Box<EvalAltResult>> {
+    let p = Path::new(path);
+    if !p
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(format!(
+            "scripts can only access files in the site folder, by relative path, not {:?}",
+            path
+        )
+        .into());
+    }
+    let full = root.join(p);
+    if let (Ok(f), Ok(r)) = (fs::canonicalize(&full), fs::canonicalize(root)) {
+        if !f.starts_with(r) {
+            return Err(format!("{:?} is outside of the site folder", path).into());
+        }
+    }
+    Ok(full)
+}
@@ -0,0 +1,260 @@
+use crate::errors::*;
+use error_chain::bail;
+use std::env;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Splits a command line into arguments following POSIX shell quoting rules:
+/// words are separated by whitespace, `'...'` is taken literally, and `"..."` and
+/// backslashes quote everything but `$`. `~` at the start of a word becomes the
+/// home directory, and `$VAR` or `${VAR}` outside single quotes becomes the value of
+/// the environment variable (as part of the same argument, not split into several).
+pub fn split(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' | '\r' => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+                continue;
+            }
+            '\'' => loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some(c) => word.push(c),
+                    None => bail!("unterminated single quote in command {:?}", line),
+                }
+            },
+            '"' => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.peek() {
+                        Some(&c) if matches!(c, '$' | '`' | '"' | '\\') => {
+                            word.push(c);
+                            chars.next();
+                        }
+                        Some('\n') => {
+                            chars.next();
+                        }
+                        _ => word.push('\\'),
+                    },
+                    Some('$') => word.push_str(&variable(&mut chars, line)?),
+                    Some(c) => word.push(c),
+                    None => bail!("unterminated double quote in command {:?}", line),
+                }
+            },
+            '\\' => match chars.next() {
+                Some('\n') => {}
+                Some(c) => word.push(c),
+                None => bail!("command {:?} ends with a backslash", line),
+            },
+            '$' => word.push_str(&variable(&mut chars, line)?),
+            '~' if !in_word && matches!(chars.peek(), None | Some('/' | ' ' | '\t' | '\n')) => {
+                word.push_str(&home()?)
+            }
+            c => word.push(c),
+        }
+        in_word = true;
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Expands `~` at the start of a single word, such as a path, and `$VAR` or `${VAR}`
+/// anywhere in it. Nothing else is special.
+pub fn expand(word: &str) -> Result<String> {
+    let mut res = String::new();
+    let mut chars = word.chars().peekable();
+    if word == "~" || word.starts_with("~/") {
+        res.push_str(&home()?);
+        chars.next();
+    }
+    while let Some(c) = chars.next() {
+        match c {
+            '$' => res.push_str(&variable(&mut chars, word)?),
+            c => res.push(c),
+        }
+    }
+    Ok(res)
+}
+
+/// Reads a variable name after a `$` and returns the variable's value.
+/// A `$` that isn't followed by a name is kept as it is.
+fn variable(chars: &mut Peekable<Chars>, line: &str) -> Result<String> {
+    let mut name = String::new();
+    if chars.peek() == Some(&'{') {
+        chars.next();
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some(c) => name.push(c),
+                None => bail!("unterminated `${{` in {:?}", line),
+            }
+        }
+    } else {
+        while let Some(&c) = chars.peek() {
+            let valid =
+                c == '_' || c.is_ascii_alphabetic() || (!name.is_empty() && c.is_ascii_digit());
+            if !valid {
+                break;
+            }
+            name.push(c);
+            chars.next();
+        }
+        if name.is_empty() {
+            return Ok("$".into());
+        }
+    }
+    env::var(&name).chain_err(|| {
+        format!(
+            "environment variable {} (used in {:?}) is not set",
+            name, line
+        )
+    })
+}
+
+fn home() -> Result<String> {
+    env::var("HOME").chain_err(|| "can't expand `~` because HOME is not set")
+}
+
+/// Replaces `%` followed by a character in an argument with what placeholder returns
+/// for the character, and `%%` with `%`. Other uses of `%` are left alone.
+pub fn substitute(arg: &str, placeholder: &mut dyn FnMut(char) -> Option<String>) -> String {
+    let mut res = String::new();
+    let mut chars = arg.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            res.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some('%') => {
+                res.push('%');
+                chars.next();
+            }
+            Some(&p) => match placeholder(p) {
+                Some(v) => {
+                    res.push_str(&v);
+                    chars.next();
+                }
+                None => res.push('%'),
+            },
+            None => res.push('%'),
+        }
+    }
+    res
+}
+
+/// Joins arguments into a command line that split() turns back into them.
+pub fn join(args: &[String]) -> String {
+    args.iter()
+        .map(|a| {
+            if !a.is_empty()
+                && a.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+%@".contains(c))
+            {
+                a.clone()
+            } else {
+                format!("'{}'", a.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(line: &str) -> Vec<String> {
+        split(line).unwrap()
+    }
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(
+            words("  pandoc -o\tout.html \n in.md "),
+            ["pandoc", "-o", "out.html", "in.md"]
+        );
+        assert!(words("").is_empty());
+    }
+
+    #[test]
+    fn quotes() {
+        assert_eq!(
+            words(r#"echo 'a b' "c d" e'f'"g""#),
+            ["echo", "a b", "c d", "efg"]
+        );
+        assert_eq!(words(r#"'' """#), ["", ""]);
+        assert_eq!(words(r#"'$HOME \"'"#), [r#"$HOME \""#]);
+    }
+
+    #[test]
+    fn backslashes() {
+        assert_eq!(words(r"a\ b c\\d \'"), ["a b", r"c\d", "'"]);
+        assert_eq!(words(r#""a\"b\\c\d\$x""#), [r#"a"b\c\d$x"#]);
+        assert_eq!(words("a\\\nb"), ["ab"]);
+    }
+
+    #[test]
+    fn variables() {
+        env::set_var("SPAN_TEST_SHELL", "x y");
+        assert_eq!(words("a$SPAN_TEST_SHELL/b"), ["ax y/b"]);
+        assert_eq!(
+            words("\"${SPAN_TEST_SHELL}1\" '$SPAN_TEST_SHELL'"),
+            ["x y1", "$SPAN_TEST_SHELL"]
+        );
+        assert_eq!(words("$ $1 a$"), ["$", "$1", "a$"]);
+        assert_eq!(
+            expand("$SPAN_TEST_SHELL-${SPAN_TEST_SHELL}").unwrap(),
+            "x y-x y"
+        );
+        assert!(split("$SPAN_TEST_UNSET").is_err());
+        assert!(expand("${SPAN_TEST_UNSET}").is_err());
+    }
+
+    #[test]
+    fn home() {
+        let home = env::var("HOME").unwrap();
+        assert_eq!(
+            words("~ ~/a a~ ~b '~'"),
+            [&*home, &format!("{}/a", home), "a~", "~b", "~"]
+        );
+        assert_eq!(expand("~/a b").unwrap(), format!("{}/a b", home));
+        assert_eq!(expand("a/~").unwrap(), "a/~");
+        assert_eq!(expand("~b").unwrap(), "~b");
+    }
+
+    #[test]
+    fn unterminated() {
+        for line in ["'a", "\"a", "a\\", "${A", "\"${A\""] {
+            assert!(split(line).is_err(), "{:?}", line);
+        }
+        assert!(expand("${A").is_err());
+    }
+
+    #[test]
+    fn substitutes_placeholders() {
+        let mut placeholder = |c| match c {
+            's' => Some("main".to_string()),
+            'e' => Some("%e".to_string()),
+            _ => None,
+        };
+        assert_eq!(substitute("%s.%e", &mut placeholder), "main.%e");
+        assert_eq!(substitute("100%% %x %", &mut placeholder), "100% %x %");
+        assert_eq!(substitute("%%s", &mut placeholder), "%s");
+    }
+
+    #[test]
+    fn join_round_trips() {
+        let args = ["a b", "", "it's", "$x", "--opt=v", "~"].map(String::from);
+        assert_eq!(words(&join(&args)), args);
+    }
+}
@@ -1,239 +1,601 @@
+use crate::call::{self, Found};
+use crate::diagnostic::Diagnostic;
 use crate::errors::*;
+use crate::frontmatter::FrontMatter;
 use error_chain::bail;
 use lazy_static::lazy_static;
 use regex::{Captures, Regex};
 use serde_yaml::{Mapping, Value};
-use std::io::BufRead;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
+use crate::scripting::Scripts;
 use crate::vfs::Folder;
 
+/// Folders and values that snippet expansion reads from.
+pub struct Context<'a> {
+    pub snippets: &'a Folder,
+    pub contents: &'a Folder,
+    /// Site-wide values, available to snippets as `site.*`.
+    pub site: &'a Value,
+    /// Scripts that define the filters in `$%{key | filter}`.
+    pub scripts: &'a Scripts,
+}
+
 /// Contains snippet-related data.
 #[derive(Clone, Default)]
 pub struct Snippet {
-    name: String,
+    path: PathBuf,
     contents: Vec<u8>,
     metadata_path: Option<String>,
     parameters: Mapping,
 }
 
+/// A problem found while expanding a snippet, before it is tied to the call site.
+struct Problem {
+    message: String,
+    key: Option<String>,
+}
+
+/// The parameter declarations in a snippet's front matter.
+#[derive(serde::Deserialize)]
+struct Declarations {
+    params: Option<HashMap<String, Parameter>>,
+}
+
+/// A parameter declared in a snippet's front matter.
+#[derive(serde::Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Parameter {
+    #[serde(rename = "type")]
+    kind: ParameterType,
+    default: Option<Value>,
+    required: bool,
+}
+
+#[derive(serde::Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum ParameterType {
+    #[default]
+    Any,
+    String,
+    Number,
+    #[serde(alias = "boolean")]
+    Bool,
+    #[serde(alias = "sequence")]
+    List,
+    #[serde(alias = "mapping")]
+    Map,
+}
+
+impl ParameterType {
+    fn matches(self, v: &Value) -> bool {
+        let v = match v {
+            Value::Tagged(t) => &t.value,
+            v => v,
+        };
+        matches!(
+            (self, v),
+            (ParameterType::Any, _)
+                | (ParameterType::String, Value::String(_))
+                | (ParameterType::Number, Value::Number(_))
+                | (ParameterType::Bool, Value::Bool(_))
+                | (ParameterType::List, Value::Sequence(_))
+                | (ParameterType::Map, Value::Mapping(_))
+        )
+    }
+}
+
+impl std::fmt::Display for ParameterType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            ParameterType::Any => "any value",
+            ParameterType::String => "a string",
+            ParameterType::Number => "a number",
+            ParameterType::Bool => "a boolean",
+            ParameterType::List => "a list",
+            ParameterType::Map => "a mapping",
+        })
+    }
+}
+
+impl Problem {
+    fn new(message: String) -> Problem {
+        Problem { message, key: None }
+    }
+}
+
+/// Returns the path used to look up snippets for a source file,
+/// which is the file's path within contents/.
+fn lookup_path(filepath: &Path) -> PathBuf {
+    filepath.iter().skip(1).collect()
+}
+
+/// Collects the strings in a metadata value, in the order they appear in it.
+fn strings_in<'a>(value: &'a mut Value, strings: &mut Vec<&'a mut String>) {
+    match value {
+        Value::String(s) => strings.push(s),
+        Value::Sequence(seq) => seq.iter_mut().for_each(|v| strings_in(v, strings)),
+        Value::Mapping(map) => map.iter_mut().for_each(|(_, v)| strings_in(v, strings)),
+        Value::Tagged(t) => strings_in(&mut t.value, strings),
+        _ => {}
+    }
+}
+
+/// Checks a value against the type of the parameter it's for, if that parameter is
+/// declared. For collection calls, `file` is the file whose metadata the value is from.
+fn check_type(
+    declared: &HashMap<String, Parameter>,
+    name: &str,
+    value: &Value,
+    file: Option<&Path>,
+) -> Option<Problem> {
+    let p = declared.get(name)?;
+    if p.kind.matches(value) {
+        return None;
+    }
+    Some(Problem {
+        message: match file {
+            Some(f) => format!("`{}` in {} should be {}", name, f.display(), p.kind),
+            None => format!("argument `{}` should be {}", name, p.kind),
+        },
+        key: Some(name.to_string()),
+    })
+}
+
 impl Snippet {
-    /// Processes the contents of a source file that might include snippets.
-    /// Returns the source file with snippet syntax replaced by the expanded snippet.
-    pub fn process_contents(fs: &Folder, filepath: PathBuf, contents: Vec<u8>) -> Result<Vec<u8>> {
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"\$%%\{(.*:)?(.+)\(([\w, ]*)\)\}").unwrap();
-            // full example $%%{path/to/folder:snippet(val1: x, val2: y)}
-            // group 1 = path/to/folder:
-            // group 2 = snippet
-            // group 3 = val1: x, val2: y
+    /// Processes the body of a source file that might include snippets.
+    /// Returns the body with snippet syntax replaced by the expanded snippet.
+    /// Markdown code is left alone, unless the front matter sets `snippets_in_code: true`.
+    pub fn process_contents(
+        ctx: &Context,
+        filepath: PathBuf,
+        contents: &[u8],
+        front_matter: &FrontMatter,
+    ) -> Result<Vec<u8>> {
+        let text = std::str::from_utf8(contents)
+            .chain_err(|| format!("expected {:?} to be UTF8", filepath))?;
+        let body_start = front_matter.body_start;
+        // code is left alone unless the file opts out with `snippets_in_code: true`
+        let in_code = front_matter
+            .metadata
+            .get("snippets_in_code")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+
+        let expanded = Snippet::expand(
+            ctx,
+            &lookup_path(&filepath),
+            &text[body_start..],
+            !in_code,
+            &|message, start, end| {
+                Diagnostic::new(
+                    message,
+                    filepath.clone(),
+                    text,
+                    body_start + start,
+                    body_start + end,
+                )
+            },
+        );
+        match expanded {
+            Ok(body) => Ok(body.into_bytes()),
+            Err(d) => bail!(ErrorKind::Diagnostics(d)),
         }
+    }
 
-        let mut errors = Vec::new();
-        let result = RE
-            .replace_all(
-                std::str::from_utf8(&contents).expect("Expected file contents to be UTF8"),
-                |m: &Captures| match Snippet::extract_snippet(m) {
-                    Ok(mut snippet) => {
-                        let mut find_filepath = filepath.iter().skip(2).collect::<PathBuf>();
-                        find_filepath.set_file_name(snippet.name.clone());
-
-                        match fs.find(find_filepath.clone()) {
-                            None => panic!("snippet doesn't exist"),
-                            Some((_, c)) => snippet.contents = c,
+    /// Processes a template that might include snippets, for rendering the source file
+    /// at filepath. Snippets are looked up the same way as for the source file itself.
+    pub fn process_template(
+        ctx: &Context,
+        template: PathBuf,
+        filepath: PathBuf,
+        contents: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let text = std::str::from_utf8(&contents)
+            .chain_err(|| format!("expected {:?} to be UTF8", template))?;
+        match Snippet::expand(
+            ctx,
+            &lookup_path(&filepath),
+            text,
+            false,
+            &|message, start, end| Diagnostic::new(message, template.clone(), text, start, end),
+        ) {
+            Ok(t) => Ok(t.into_bytes()),
+            Err(d) => bail!(ErrorKind::Diagnostics(d)),
+        }
+    }
+
+    /// Expands snippets inside the string values of the source file's front matter.
+    /// Returns whether any values were changed.
+    pub fn process_metadata(
+        ctx: &Context,
+        filepath: PathBuf,
+        contents: &[u8],
+        front_matter: &mut FrontMatter,
+    ) -> Result<bool> {
+        let text = std::str::from_utf8(contents)
+            .chain_err(|| format!("expected {:?} to be UTF8", filepath))?;
+        if !text[..front_matter.body_start].contains(call::CALL_START) {
+            return Ok(false);
+        }
+
+        let mut changed = false;
+        let mut diagnostics = Vec::new();
+        let mut strings = Vec::new();
+        for (_, v) in front_matter.metadata.iter_mut() {
+            strings_in(v, &mut strings);
+        }
+        // values are visited in the order they appear in the file, so each call in them is
+        // matched with the next place its text appears in the front matter
+        let mut from = 0;
+        for s in strings {
+            if !s.contains(call::CALL_START) {
+                continue;
+            }
+            let value_text = s.clone();
+            let mut calls = Vec::new();
+            for (i, _) in value_text.match_indices(call::CALL_START) {
+                let needle = value_text[i..].lines().next().unwrap_or_default();
+                if let Some(j) = text[from..front_matter.body_start].find(needle) {
+                    calls.push((i, from + j));
+                    from += j + call::CALL_START.len();
+                }
+            }
+            let locate = |message: String, start: usize, end: usize| {
+                // offsets within the value are moved to the file by the call they fall in
+                let (start, end) = match calls.iter().rev().find(|(i, _)| *i <= start) {
+                    Some((i, j)) => (j + start - i, (j + end - i).min(text.len())),
+                    None => (0, 0),
+                };
+                Diagnostic::new(message, filepath.clone(), text, start, end)
+            };
+            match Snippet::expand(ctx, &lookup_path(&filepath), &value_text, false, &locate) {
+                Ok(expanded) => {
+                    changed |= expanded != value_text;
+                    *s = expanded;
+                }
+                Err(d) => diagnostics.extend(d),
+            }
+        }
+        if !diagnostics.is_empty() {
+            bail!(ErrorKind::Diagnostics(diagnostics));
+        }
+        Ok(changed)
+    }
+
+    /// Replaces snippet calls in text with their expansions, and escaped markers with
+    /// literal markers. If skip_code is set, Markdown code is left alone.
+    /// Snippets are looked up relative to lookup (a path within contents/).
+    /// Uses diagnostic to turn a message and a byte range of text into a Diagnostic.
+    fn expand(
+        ctx: &Context,
+        lookup: &Path,
+        text: &str,
+        skip_code: bool,
+        diagnostic: &dyn Fn(String, usize, usize) -> Diagnostic,
+    ) -> std::result::Result<String, Vec<Diagnostic>> {
+        let mut diagnostics = Vec::new();
+        let mut result = String::with_capacity(text.len());
+        let mut last = 0;
+        let skip = if skip_code {
+            call::code_ranges(text)
+        } else {
+            Vec::new()
+        };
+        for found in call::find_calls(text, &skip) {
+            match found {
+                Found::Escape(backslash) => {
+                    result.push_str(&text[last..backslash]);
+                    last = backslash + 1;
+                }
+                Found::Call(call) => {
+                    result.push_str(&text[last..call.start]);
+                    last = call.end;
+
+                    let mut find_filepath = lookup.to_path_buf();
+                    find_filepath.set_file_name(call.name.clone());
+                    let snippet = match ctx.snippets.find(find_filepath.clone()) {
+                        None => {
+                            let mut d = diagnostic(
+                                format!("snippet `{}` does not exist", call.name),
+                                call.start,
+                                call.end,
+                            );
+                            d.notes.push(format!(
+                                "looked for `{}` in {} and its parent folders",
+                                call.name,
+                                ctx.snippets
+                                    .path
+                                    .join(find_filepath.parent().unwrap_or(&PathBuf::new()))
+                                    .display()
+                            ));
+                            diagnostics.push(d);
+                            continue;
                         }
+                        Some((p, c)) => Snippet {
+                            path: ctx.snippets.path.join(p),
+                            contents: c,
+                            metadata_path: call.metadata_path.clone(),
+                            parameters: call.arguments.clone(),
+                        },
+                    };
 
-                        match snippet.process_snippet(fs) {
-                            Ok(s) => s,
-                            Err(e) => {
-                                errors.push(e);
-                                "".to_string()
+                    let path = snippet.path.clone();
+                    match snippet.process_snippet(ctx) {
+                        Ok(s) => result.push_str(&s),
+                        Err(problems) => {
+                            for p in problems {
+                                let mut d = diagnostic(p.message, call.start, call.end);
+                                d.snippet = Some(path.clone());
+                                d.key = p.key;
+                                diagnostics.push(d);
                             }
                         }
                     }
-                    Err(e) => {
-                        errors.push(e);
-                        "".to_string()
-                    }
-                },
-            )
-            .as_bytes()
-            .to_vec();
-        if errors.len() != 0 {
-            bail!(errors
-                .iter()
-                .map(|x| x.to_string())
-                .collect::<Vec<String>>()
-                .join("\n"));
+                }
+                Found::Malformed(e) => diagnostics.push(diagnostic(
+                    format!("malformed snippet call: {}", e.message),
+                    e.offset,
+                    e.offset + 1,
+                )),
+            }
+        }
+        result.push_str(&text[last..]);
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
         }
         Ok(result)
     }
 
-    /// Extracts snippet data from a regex match.
-    fn extract_snippet(matches: &Captures) -> Result<Snippet> {
-        Ok(Snippet {
-            name: match matches.get(1) {
-                Some(n) => n.as_str().to_string(),
-                None => bail!("snippet is missing name"),
-            },
-            metadata_path: match matches.get(1) {
-                Some(p) => {
-                    let x = p.as_str();
-                    Some(x[..x.len() - 1].to_string())
-                }
-                None => None,
-            },
-            parameters: match matches.get(4) {
-                Some(p) => {
-                    let mut params = Mapping::new();
-                    for x in p.as_str().split(",") {
-                        let mut name_args = x.splitn(2, ":");
-                        let name = name_args.next().ok_or("failed to parse parameters")?;
-                        let arg = name_args.next().ok_or("failed to parse parameters")?;
-                        params.insert(
-                            Value::String(name.to_string()),
-                            Value::String(arg.to_string()),
-                        );
-                    }
-                    params
-                }
-                None => Mapping::new(),
-            },
-            contents: Vec::new(),
-        })
+    /// Splits the snippet's front matter from its body and returns the parameters it
+    /// declares. Snippets without declared parameters accept any arguments.
+    fn declarations(mut self) -> std::result::Result<(Snippet, Declarations), Vec<Problem>> {
+        let front_matter = FrontMatter::parse(&self.path, &self.contents)
+            .map_err(|e| vec![Problem::new(e.to_string())])?;
+        self.contents = front_matter.body;
+        match serde_yaml::from_value::<Declarations>(Value::Mapping(front_matter.metadata)) {
+            Ok(d) => Ok((self, d)),
+            Err(e) => Err(vec![Problem::new(format!(
+                "invalid parameter declarations in {}: {}",
+                self.path.display(),
+                e
+            ))]),
+        }
     }
 
-    /// Gets and parses YAML metadata from the source file.
-    fn extract_metadata(contents: Vec<u8>) -> Result<String> {
-        let mut lines = contents.lines();
-        match lines.next() {
-            Some(Ok(s)) => {
-                if s == "---" {
-                    let mut metadata = Vec::new();
-                    let mut unterminated = true;
-                    for l in lines {
-                        let line = l.chain_err(|| "could not read file")?;
-                        if line == "---" || line == "..." {
-                            unterminated = false;
-                            break;
-                        } else {
-                            metadata.push(line);
-                        }
-                    }
-                    if !unterminated {
-                        return Ok(metadata.join("\n\n"));
+    /// Checks the call's arguments against the declared parameters.
+    fn check_arguments(&self, declared: &HashMap<String, Parameter>) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        for (k, v) in self.parameters.iter() {
+            let name = k.as_str().unwrap_or_default();
+            if !declared.contains_key(name) {
+                let mut expected = declared.keys().cloned().collect::<Vec<_>>();
+                expected.sort();
+                problems.push(Problem {
+                    message: if expected.is_empty() {
+                        format!("unknown argument `{}` (snippet takes no arguments)", name)
                     } else {
-                        bail!("metadata does not terminate")
-                    }
-                } else {
-                    return Ok(String::new());
-                }
+                        format!(
+                            "unknown argument `{}` (expected one of `{}`)",
+                            name,
+                            expected.join("`, `")
+                        )
+                    },
+                    key: Some(name.to_string()),
+                })
             }
-            _ => return Ok(String::new()),
+            problems.extend(check_type(declared, name, v, None));
         }
+        problems
     }
 
-    /// Processes a snippet using the source folder.
+    /// Reports declared parameters that are required but missing and fills in defaults
+    /// for the others. For collection calls, `file` is the file whose metadata was added
+    /// to the arguments.
+    fn apply_defaults(
+        mut self,
+        declared: &HashMap<String, Parameter>,
+        file: Option<&Path>,
+    ) -> std::result::Result<Snippet, Vec<Problem>> {
+        let mut problems = Vec::new();
+        let mut names = declared.keys().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            let p = &declared[name];
+            let key = Value::String(name.clone());
+            if self.parameters.contains_key(&key) {
+                continue;
+            }
+            if p.required {
+                problems.push(Problem {
+                    message: match file {
+                        Some(f) => format!(
+                            "missing required argument `{}`, which {} doesn't set either",
+                            name,
+                            f.display()
+                        ),
+                        None => format!("missing required argument `{}`", name),
+                    },
+                    key: Some(name.clone()),
+                });
+            } else if let Some(ref default) = p.default {
+                self.parameters.insert(key, default.clone());
+            }
+        }
+        if !problems.is_empty() {
+            return Err(problems);
+        }
+        Ok(self)
+    }
+
+    /// Processes a snippet using the source folders.
     /// Returns snippet expansion (including metadata expansion if necessary).
-    fn process_snippet(self, fs: &Folder) -> Result<String> {
-        match self.metadata_path {
+    fn process_snippet(self, ctx: &Context) -> std::result::Result<String, Vec<Problem>> {
+        let (snippet, Declarations { params: declared }) = self.declarations()?;
+        if let Some(ref d) = declared {
+            let problems = snippet.check_arguments(d);
+            if !problems.is_empty() {
+                return Err(problems);
+            }
+        }
+        match snippet.metadata_path {
             Some(ref mp) => {
+                let files = ctx
+                    .contents
+                    .get_globs(&vec![mp.to_owned() + "/*"])
+                    .map_err(|e| vec![Problem::new(e.to_string())])?;
+                if files.is_empty() {
+                    return Err(vec![Problem::new(format!(
+                        "could not find any files within {}",
+                        ctx.contents.path.join(mp).display()
+                    ))]);
+                }
+                let mut files = files.into_iter().collect::<Vec<_>>();
+                files.sort();
+
                 let mut snippet_result: Vec<String> = Vec::new();
-                if let Err(_) = fs.map_globs(
-                    &vec![mp.to_owned() + "/*"],
-                    &mut |_, c| {
-                        let metadata = Snippet::extract_metadata(c)?;
-                        let data_map: Mapping = serde_yaml::from_str(&metadata)
-                            .chain_err(|| "Failed to parse metadata")?;
-                        let mut temp = self.clone();
-                        temp.parameters.extend(data_map);
-                        snippet_result.push(temp.process_args()?);
-                        Ok(None)
-                    },
-                    &mut |_, _| Ok(None),
-                ) {
-                    bail!("Could not find any files within the path {}", mp);
+                let mut problems = Vec::new();
+                for (fp, c) in files {
+                    let data_map = match FrontMatter::parse(&fp, &c) {
+                        Ok(f) => f.metadata,
+                        Err(e) => {
+                            problems.push(Problem::new(e.to_string()));
+                            continue;
+                        }
+                    };
+                    let mut temp = snippet.clone();
+                    if let Some(ref d) = declared {
+                        let wrong = data_map
+                            .iter()
+                            .flat_map(|(k, v)| {
+                                check_type(d, k.as_str().unwrap_or_default(), v, Some(&fp))
+                            })
+                            .collect::<Vec<_>>();
+                        if !wrong.is_empty() {
+                            problems.extend(wrong);
+                            continue;
+                        }
+                    }
+                    temp.parameters.extend(data_map);
+                    if let Some(ref d) = declared {
+                        temp = match temp.apply_defaults(d, Some(&fp)) {
+                            Ok(t) => t,
+                            Err(p) => {
+                                problems.extend(p);
+                                continue;
+                            }
+                        };
+                    }
+                    match temp.process_args(ctx) {
+                        Ok(s) => snippet_result.push(s),
+                        Err(p) => problems.extend(p),
+                    }
+                }
+                if !problems.is_empty() {
+                    return Err(problems);
                 }
                 Ok(snippet_result.join("\n\n"))
             }
-            None => self.process_args(),
+            None => match declared {
+                Some(ref d) => snippet.apply_defaults(d, None)?.process_args(ctx),
+                None => snippet.process_args(ctx),
+            },
         }
     }
 
-    fn process_args(self) -> Result<String> {
+    /// Replaces `$%{key.chain}` in the snippet with the matching parameter values, passed
+    /// through the filters in `$%{key.chain | filter | ...}`.
+    /// A backslash before the `$` outputs the marker literally instead.
+    fn process_args(self, ctx: &Context) -> std::result::Result<String, Vec<Problem>> {
         lazy_static! {
-            static ref RE: Regex = Regex::new(r"\$%\{(.+)\}").unwrap();
+            static ref RE: Regex = Regex::new(r"(\\*)\$%\{([^}]+)\}").unwrap();
         }
         let mut errors = Vec::new();
+        let contents = std::str::from_utf8(&self.contents)
+            .map_err(|_| vec![Problem::new("expected snippet contents to be UTF8".into())])?;
         let x = RE
-            .replace_all(
-                std::str::from_utf8(&self.contents)
-                    .chain_err(|| "Expected snippet contents to be UTF8")?,
-                |m: &Captures| match m.get(1) {
-                    Some(mat) => {
-                        let mut current: Value = Value::Mapping(self.parameters.clone());
-                        for v in mat.as_str().split(".") {
-                            let gr;
-                            let k = v.parse::<usize>();
-                            match k {
-                                Ok(x) => gr = current.get(x),
-                                Err(_) => gr = current.get(v),
-                            }
-
-                            match gr {
-                                None | Some(Value::Null) => {
-                                    errors.push(format!(
-                                        "Key {} does not exist (part of key chain \"{}\")",
-                                        v,
-                                        mat.as_str()
-                                    ));
-                                    return "".to_string();
-                                }
-                                Some(Value::Tagged(x)) => current = x.clone().value,
-                                Some(x) => current = x.clone(),
-                            }
-                        }
-
-                        while let Value::Tagged(x) = current {
-                            // unwrap tagged value
-                            current = x.value;
-                        }
-
-                        match current {
-                            Value::Null => "".to_string(),
-                            Value::Bool(x) => x.to_string(),
-                            Value::Number(x) => x.to_string(),
-                            Value::String(x) => x,
-                            Value::Sequence(x) => {
-                                errors.push(format!(
-                                    "Value is a sequence and therefore cannot be treated like a string: {:#?}",
-                                    x,
-                                ));
-                                "".to_string()
-                            },
-                            Value::Mapping(x) => {
-                                errors.push(format!(
-                                    "Value is a mapping and therefore cannot be treated like a string: {:#?}",
-                                    x,
-                                ));
-                                "".to_string()
-                            },
-                            Value::Tagged(x) => {
-                                errors.push(format!(
-                                    "Value is a mapping and therefore cannot be treated like a string: {:#?}",
-                                    x,
-                                ));
-                                "".to_string()
-                            },
-                        }
+            .replace_all(contents, |m: &Captures| {
+                // an odd number of backslashes means the marker itself is escaped
+                let backslashes = &m[1];
+                if backslashes.len() % 2 == 1 {
+                    return m[0][1..].to_string();
+                }
+                let mut parts = m[2].split('|');
+                let key = parts.next().unwrap_or_default().trim();
+                let value = self.lookup(ctx, key).and_then(|v| {
+                    parts.try_fold(v, |v, filter| {
+                        ctx.scripts.filter(filter.trim(), &v).map_err(|e| Problem {
+                            message: e,
+                            key: Some(key.to_string()),
+                        })
+                    })
+                });
+                match value {
+                    Ok(s) => format!("{}{}", backslashes, s),
+                    Err(p) => {
+                        errors.push(p);
+                        "".to_string()
                     }
-                    None => "".to_string(),
-                },
-            )
+                }
+            })
             .to_string();
-        if errors.len() != 0 {
-            bail!(errors.join("\n"));
+        if !errors.is_empty() {
+            return Err(errors);
         }
         Ok(x)
     }
+
+    /// Gets the parameter value for a key chain such as `data.authors.0.name`,
+    /// as a string. Chains starting with `site` that don't match a parameter
+    /// look up site-wide values instead.
+    fn lookup(&self, ctx: &Context, chain: &str) -> std::result::Result<String, Problem> {
+        let missing = |v: &str| Problem {
+            message: format!("key `{}` does not exist (part of key chain `{}`)", v, chain),
+            key: Some(chain.to_string()),
+        };
+        let mut keys = chain.split('.');
+        let first = keys.next().unwrap_or_default();
+        let mut current = match self.parameters.get(first) {
+            None | Some(Value::Null) if first == "site" => ctx.site,
+            None | Some(Value::Null) => return Err(missing(first)),
+            Some(v) => v,
+        };
+        for v in keys {
+            while let Value::Tagged(x) = current {
+                // unwrap tagged value
+                current = &x.value;
+            }
+            let gr = match v.parse::<usize>() {
+                Ok(x) => current.get(x),
+                Err(_) => current.get(v),
+            };
+
+            match gr {
+                None | Some(Value::Null) => return Err(missing(v)),
+                Some(x) => current = x,
+            }
+        }
+
+        while let Value::Tagged(x) = current {
+            // unwrap tagged value
+            current = &x.value;
+        }
+
+        let kind = match current {
+            Value::Null => return Ok("".to_string()),
+            Value::Bool(x) => return Ok(x.to_string()),
+            Value::Number(x) => return Ok(x.to_string()),
+            Value::String(x) => return Ok(x.clone()),
+            Value::Sequence(_) => "sequence",
+            Value::Mapping(_) | Value::Tagged(_) => "mapping",
+        };
+        Err(Problem {
+            message: format!(
+                "value of `{}` is a {} and therefore cannot be treated like a string",
+                chain, kind,
+            ),
+            key: Some(chain.to_string()),
+        })
+    }
 }
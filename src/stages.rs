@@ -0,0 +1,276 @@
+use crate::builtins;
+use crate::cache::Cache;
+use crate::config;
+use crate::errors::*;
+use crate::frontmatter::FrontMatter;
+use crate::scripting::Scripts;
+use crate::shell;
+use crate::vfs::Folder;
+use error_chain::bail;
+use serde_yaml::Mapping;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+
+/// The state of a build, which each stage works on in turn.
+pub struct Build {
+    pub config: config::Config,
+    /// The settings from `_span.yml` files, keyed by folder within contents/.
+    pub directories: BTreeMap<PathBuf, config::DirConfig>,
+    /// The site folder without contents/: templates/, snippets/, data/ and so on.
+    pub source: Folder,
+    /// The files in contents/ that haven't been put in the built site yet.
+    pub contents: Folder,
+    /// The built site so far.
+    pub output: Folder,
+    /// Whether pandoc has run. Stages work on contents before, and on output after.
+    pub rendered: bool,
+    /// Paths of the files in contents/ that are passed through, so they keep their URL.
+    pub passed_through: HashSet<PathBuf>,
+    pub cache: Cache,
+    pub scripts: Scripts,
+}
+
+impl Build {
+    /// Returns the files that stages work on now, as a folder whose paths start with
+    /// `contents/`, like those of contents/ itself.
+    pub fn files(&mut self) -> &mut Folder {
+        match self.rendered {
+            true => &mut self.output,
+            false => &mut self.contents,
+        }
+    }
+}
+
+/// A step of the build, like running pandoc or minifying the built site.
+pub trait Stage {
+    fn run(&self, build: &mut Build) -> Result<()>;
+}
+
+/// See config::StageConfig::Minify.
+pub struct Minify {
+    pub files: Vec<String>,
+}
+
+impl Stage for Minify {
+    fn run(&self, build: &mut Build) -> Result<()> {
+        let files = build.files();
+        *files = files.map_globs(
+            &self.files,
+            &mut |fp, c| {
+                let minified = match fp.extension().and_then(|e| e.to_str()) {
+                    Some("html" | "htm") => builtins::minify_html(&c),
+                    Some("css") => builtins::minify_css(&c),
+                    _ => c,
+                };
+                Ok(Some((fp, minified)))
+            },
+            &mut |fp, c| Ok(Some((fp, c))),
+        )?;
+        Ok(())
+    }
+}
+
+/// See config::StageConfig::Copy and config::StageConfig::Rename, which doesn't
+/// keep the original files.
+pub struct Copy {
+    pub files: Vec<String>,
+    pub to: String,
+    pub keep: bool,
+}
+
+impl Stage for Copy {
+    fn run(&self, build: &mut Build) -> Result<()> {
+        let files = build.files();
+        let root = files.path.clone();
+        let verb = if self.keep { "copy" } else { "rename" };
+        // target -> (source, contents)
+        let mut copies: BTreeMap<PathBuf, (PathBuf, Vec<u8>)> = BTreeMap::new();
+        *files = files.map_globs(
+            &self.files,
+            &mut |fp, c| {
+                let within: PathBuf = fp.iter().skip(1).collect();
+                let to = target(&self.to, &within)?;
+                if let Some((other, _)) = copies.get(&to) {
+                    bail!(
+                        "can't {} both {:?} and {:?} to {:?}",
+                        verb,
+                        other,
+                        fp,
+                        root.join(&to)
+                    );
+                }
+                copies.insert(to, (fp.clone(), c.clone()));
+                Ok(if self.keep { Some((fp, c)) } else { None })
+            },
+            &mut |fp, c| Ok(Some((fp, c))),
+        )?;
+        for (to, (from, c)) in copies {
+            if files.get_file(&to).is_some() {
+                bail!(
+                    "can't {} {:?} to {:?}, which already exists",
+                    verb,
+                    from,
+                    root.join(&to)
+                );
+            }
+            files.push(to, c)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the path a copied or renamed file goes to, relative to the folder stages
+/// work on: `to`, in which `%d`, `%s` and `%e` are the folder, name without extension
+/// and extension of the file.
+fn target(to: &str, file: &Path) -> Result<PathBuf> {
+    let lossy = |p: Option<&std::ffi::OsStr>| p.unwrap_or_default().to_string_lossy().to_string();
+    let path = PathBuf::from(shell::substitute(to, &mut |c| match c {
+        'd' => Some(lossy(file.parent().map(Path::as_os_str))),
+        's' => Some(lossy(file.file_stem())),
+        'e' => Some(lossy(file.extension())),
+        _ => None,
+    }));
+    // a leading / (from an empty %d) is ignored, but leaving the folder isn't allowed
+    let mut res = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(n) => res.push(n),
+            Component::RootDir | Component::CurDir => {}
+            _ => bail!(
+                "{:?} (for {:?}) must be a path that stays within the site",
+                to,
+                file
+            ),
+        }
+    }
+    if res.file_name().is_none() {
+        bail!("{:?} (for {:?}) must be the path of a file", to, file);
+    }
+    Ok(res)
+}
+
+/// See config::StageConfig::FrontmatterInject.
+pub struct FrontmatterInject {
+    pub files: Vec<String>,
+    pub values: Mapping,
+    pub overwrite: bool,
+}
+
+impl Stage for FrontmatterInject {
+    fn run(&self, build: &mut Build) -> Result<()> {
+        let files = build.files();
+        *files = files.map_globs(
+            &self.files,
+            &mut |fp, c| {
+                let mut front_matter = FrontMatter::parse(&fp, &c)?;
+                for (k, v) in self.values.iter() {
+                    if self.overwrite || !front_matter.metadata.contains_key(k) {
+                        front_matter.metadata.insert(k.clone(), v.clone());
+                    }
+                }
+                let document = front_matter.to_yaml_document(&front_matter.body)?;
+                Ok(Some((fp, document)))
+            },
+            &mut |fp, c| Ok(Some((fp, c))),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(files: &[&str]) -> Build {
+        let config = config::Config::default();
+        let mut contents = Folder::new(PathBuf::from("contents"));
+        for f in files {
+            contents
+                .push(PathBuf::from(f), f.as_bytes().to_vec())
+                .unwrap();
+        }
+        Build {
+            scripts: Scripts::load(&config, Path::new(".")).unwrap(),
+            config,
+            directories: BTreeMap::new(),
+            source: Folder::new(PathBuf::new()),
+            output: Folder::new(PathBuf::from("contents")),
+            contents,
+            rendered: false,
+            passed_through: HashSet::new(),
+            cache: Cache::default(),
+        }
+    }
+
+    fn copy(globs: &[&str], to: &str, keep: bool) -> Copy {
+        Copy {
+            files: globs.iter().map(|g| g.to_string()).collect(),
+            to: to.into(),
+            keep,
+        }
+    }
+
+    #[test]
+    fn targets() {
+        let t = |to: &str, file: &str| target(to, Path::new(file)).map_err(|e| e.to_string());
+        assert_eq!(t("%d/%s.xml", "blog/feed.html"), Ok("blog/feed.xml".into()));
+        // %d is empty at the site root, and the / it leaves is ignored
+        assert_eq!(t("%d/%s.xml", "feed.html"), Ok("feed.xml".into()));
+        assert_eq!(t("./%s-%e.txt", "a/b.md"), Ok("b-md.txt".into()));
+        assert!(t("../%s.html", "a.md")
+            .unwrap_err()
+            .contains("stays within the site"));
+        assert!(t("%d/../../x", "a/b.md")
+            .unwrap_err()
+            .contains("stays within the site"));
+        assert!(t("%d/.", "b.md")
+            .unwrap_err()
+            .contains("must be the path of a file"));
+    }
+
+    #[test]
+    fn copies_and_renames() {
+        let mut b = build(&["blog/feed.html", "index.html"]);
+        copy(&["blog/*.html"], "%d/%s.xml", true)
+            .run(&mut b)
+            .unwrap();
+        assert!(b.contents.get_file(Path::new("blog/feed.html")).is_some());
+        assert_eq!(
+            b.contents.get_file(Path::new("blog/feed.xml")).unwrap(),
+            b"blog/feed.html"
+        );
+        copy(&["index.html"], "home.html", false)
+            .run(&mut b)
+            .unwrap();
+        assert!(b.contents.get_file(Path::new("index.html")).is_none());
+        assert!(b.contents.get_file(Path::new("home.html")).is_some());
+        // renaming a file to its own path leaves it where it is
+        copy(&["home.html"], "%s.%e", false).run(&mut b).unwrap();
+        assert!(b.contents.get_file(Path::new("home.html")).is_some());
+    }
+
+    #[test]
+    fn rejects_two_files_with_one_target() {
+        let mut b = build(&["a/x.md", "b/x.md"]);
+        let e = copy(&["*/*.md"], "%s.md", true)
+            .run(&mut b)
+            .unwrap_err()
+            .to_string();
+        // either file can come first
+        assert!(e.starts_with("can't copy both "), "{}", e);
+        assert!(e.ends_with(" to \"contents/x.md\""), "{}", e);
+        assert!(e.contains("\"contents/a/x.md\"") && e.contains("\"contents/b/x.md\""));
+    }
+
+    #[test]
+    fn rejects_overwriting_files() {
+        let mut b = build(&["favicon.png", "icon.png"]);
+        let e = copy(&["favicon.png"], "icon.png", false)
+            .run(&mut b)
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "can't rename \"contents/favicon.png\" to \"contents/icon.png\", which already exists"
+        );
+    }
+}
@@ -3,7 +3,7 @@ use error_chain::*;
 use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /// Folder is an in-memory copy of a folder.
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
@@ -31,22 +31,21 @@ impl Folder {
     /// If the filename is not specified, reads the contents
     /// of the specified folder into a Folder.
     pub fn read(pb: PathBuf) -> Result<Folder> {
-        return if pb.is_file() {
+        if pb.is_file() {
             let contents = fs::read(pb.clone()).chain_err(|| format!("could not read {:?}", pb))?;
             bincode::deserialize(&contents).chain_err(|| "err")
         } else {
-            let mut res = Folder::new(pb);
-            let paths = fs::read_dir(res.path.clone())
-                .chain_err(|| format!("could not read {:?}", res.path))?;
+            // paths are stored relative to the folder that was read, without a leading `./`
+            let mut res = Folder::new(pb.strip_prefix(".").unwrap_or(&pb).to_path_buf());
+            let paths =
+                fs::read_dir(pb.clone()).chain_err(|| format!("could not read {:?}", pb))?;
             for path in paths {
                 let p = path.chain_err(|| "could not read file path")?;
                 let file_type = p
                     .file_type()
                     .chain_err(|| format!("could not get file type of {:?}", p))?;
                 if file_type.is_dir() {
-                    let mut path = res.path.clone();
-                    path.push(p.file_name());
-                    let folder = Self::read(path)?;
+                    let folder = Self::read(pb.join(p.file_name()))?;
                     res.folders.insert(p.file_name(), folder);
                 } else if file_type.is_file() {
                     let contents = fs::read(p.path())
@@ -60,7 +59,7 @@ impl Folder {
                 };
             }
             Ok(res)
-        };
+        }
     }
     /// Maps the provided func over the Folder's contents, returning the resulting folder.
     /// Does not modify the original folder's contents.
@@ -71,7 +70,7 @@ impl Folder {
         let mut res = Folder::new(prefix.clone());
         let mut errors = Vec::new();
         for (name, contents) in self.files.iter() {
-            let mut p = self.path.clone();
+            let mut p = prefix.clone();
             p.push(name.clone());
             let c = contents.clone();
             match func(p, c) {
@@ -105,6 +104,8 @@ impl Folder {
     }
     /// Maps the provided func over file contents with matching file paths,
     /// returning the resulting folder.
+    /// Globs are matched against paths relative to this folder,
+    /// but the func is given the full path.
     /// Does not modify the original folder's contents.
     /// Leaves all other files alone.
     pub fn map_globs<F1, F2>(
@@ -121,15 +122,16 @@ impl Folder {
         let mut builder = GlobSetBuilder::new();
         for s in globs {
             builder.add(
-                GlobBuilder::new(&s)
+                GlobBuilder::new(s)
                     .literal_separator(true)
                     .build()
                     .chain_err(|| format!("couldn't create glob from {}", s))?,
             );
         }
         let set = builder.build().chain_err(|| "couldn't create glob set")?;
-        self.clone().map(PathBuf::new(), &mut |fp, c| {
-            if set.is_match(fp.clone()) {
+        let root = self.path.clone();
+        self.clone().map(root.clone(), &mut |fp, c| {
+            if set.is_match(fp.strip_prefix(&root).unwrap_or(&fp)) {
                 match_func(fp, c)
             } else {
                 no_match_func(fp, c)
@@ -143,7 +145,7 @@ impl Folder {
             globs,
             &mut |fp, c| {
                 res.insert(fp.clone(), c.clone());
-                return Ok(Some((fp, c)));
+                Ok(Some((fp, c)))
             },
             &mut |_, _| Ok(None),
         )?;
@@ -161,25 +163,26 @@ impl Folder {
         self.clone()
             .map_globs(globs, &mut |fp, c| Ok(Some((fp, c))), &mut |_, _| Ok(None))
     }
-    /// Adds a file to a folder, creating parent directories if necessary.
+    /// Adds a file to a folder, given its path relative to the folder,
+    /// creating parent directories if necessary.
     /// If the file already exists, overwrites the contents of the file.
     pub fn push(&mut self, fp: PathBuf, contents: Vec<u8>) -> Result<()> {
         let mut t = self;
-        while let Some(c) = fp.parent() {
-            let c = c.as_os_str();
-            if !t.folders.contains_key(c) {
-                let p = &mut t.path;
-                p.push(c);
-                t.folders.insert(c.into(), Folder::new(p.to_path_buf()));
+        if let Some(parent) = fp.parent() {
+            for c in parent {
+                if !t.folders.contains_key(c) {
+                    let p = t.path.join(c);
+                    t.folders.insert(c.into(), Folder::new(p));
+                }
+                t = t
+                    .folders
+                    .get_mut(c)
+                    .ok_or(format!("failed to add folder {:?}", c))?;
             }
-            t = t
-                .folders
-                .get_mut(c)
-                .ok_or(format!("failed to add folder{:?}", c))?;
         }
         t.files.insert(
             fp.file_name()
-                .ok_or(format!("failed to add file{:?}", fp))?
+                .ok_or(format!("failed to add file {:?}", fp))?
                 .to_os_string(),
             contents,
         );
@@ -187,11 +190,27 @@ impl Folder {
     }
     /// Joins two folders. If there is overlap, the second folder's contents are used.
     pub fn join(x: Folder, y: Folder) -> Result<Folder> {
-        let res = x.clone();
-        y.map(PathBuf::new(), &mut |fp, c| Ok(Some((fp, c))))?;
+        let mut res = x;
+        res.files.extend(y.files);
+        for (name, folder) in y.folders {
+            let joined = match res.folders.remove(&name) {
+                Some(f) => Folder::join(f, folder)?,
+                None => folder,
+            };
+            res.folders.insert(name, joined);
+        }
         Ok(res)
     }
-
+    /// Gets the contents of the file at the given path, relative to this folder.
+    pub fn get_file(&self, file: &Path) -> Option<&Vec<u8>> {
+        let mut folder = self;
+        if let Some(parent) = file.parent() {
+            for c in parent {
+                folder = folder.folders.get(c)?;
+            }
+        }
+        folder.files.get(file.file_name()?)
+    }
     /// Gets the path to the "most matching" file and its contents.
     /// If it can't find anything, returns None.
     /// See the README for details on the algorithm.
@@ -235,13 +254,14 @@ impl Folder {
             Ok(())
         } else {
             match self.clone().map(PathBuf::new(), &mut |fp, c| {
+                let out = path.join(&fp);
                 fs::create_dir_all(
-                    fp.parent()
-                        .ok_or(format!("could not get parent of path {:?}", fp))?,
+                    out.parent()
+                        .ok_or(format!("could not get parent of path {:?}", out))?,
                 )
-                .chain_err(|| format!("could not create dirs for {:?}", fp))?;
-                fs::write(fp.clone(), c.clone())
-                    .chain_err(|| format!("couldn't write to {:?}", fp))?;
+                .chain_err(|| format!("could not create dirs for {:?}", out))?;
+                fs::write(out.clone(), c.clone())
+                    .chain_err(|| format!("couldn't write to {:?}", out))?;
                 Ok(Some((fp, c)))
             }) {
                 Err(e) => {